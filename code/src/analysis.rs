@@ -0,0 +1,392 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::decompile::Entry;
+use crate::instruction::Instruction;
+use crate::opcodes::{OpCodes, Role};
+
+// Control flow over a linear disassembly. Only literal jump targets are
+// followed, jumps through registers end a block without successors.
+// Field names are part of the export format, see export.rs.
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Block {
+    pub start: usize,
+    /// address after the last instruction
+    pub end: usize,
+    /// starts of the blocks execution can continue in
+    pub successors: Vec<usize>,
+}
+
+/// Jumps and anything that doesn't fall through end a block, calls don't
+fn ends_block(inst: &Instruction) -> bool {
+    let jump = inst.op.operand(Role::JumpTarget).is_some() && inst.op != OpCodes::call;
+    jump || !inst.falls_through()
+}
+
+/// Splits the instructions in `entries` into basic blocks. Blocks start at
+/// jump targets, after anything that doesn't fall through and after data.
+pub fn basic_blocks(entries: &[Entry]) -> Vec<Block> {
+    let mut leaders = BTreeSet::new();
+    let mut after_break = true;
+    for entry in entries {
+        match entry {
+            Entry::Code(inst) => {
+                if after_break {
+                    leaders.insert(inst.addr);
+                }
+                match inst.target() {
+                    Some(target) if inst.op != OpCodes::call => {
+                        leaders.insert(target);
+                    }
+                    _ => (),
+                }
+                after_break = ends_block(inst);
+            }
+            Entry::Word(_, _) => after_break = true,
+        }
+    }
+
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut current: Option<Block> = None;
+    for entry in entries {
+        let inst = match entry {
+            Entry::Code(inst) => inst,
+            Entry::Word(_, _) => {
+                blocks.extend(current.take());
+                continue;
+            }
+        };
+        if leaders.contains(&inst.addr) {
+            if let Some(mut block) = current.take() {
+                // fell into a jump target
+                block.successors.push(inst.addr);
+                blocks.push(block);
+            }
+        }
+        let block = current.get_or_insert(Block {
+            start: inst.addr,
+            end: inst.addr,
+            successors: Vec::new(),
+        });
+        block.end = inst.next();
+        if ends_block(inst) {
+            if let Some(target) = inst.target() {
+                block.successors.push(target);
+            }
+            if inst.falls_through() {
+                block.successors.push(inst.next());
+            }
+            blocks.extend(current.take());
+        }
+    }
+    blocks.extend(current.take());
+    blocks
+}
+
+/// Code reachable from a call target, or from the first instruction
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Function {
+    pub start: usize,
+    /// end of the last block
+    pub end: usize,
+    /// block starts in address order
+    pub blocks: Vec<usize>,
+}
+
+/// Follows block successors from every call target and the first
+/// instruction. Jumps to another function's start are tail calls and
+/// aren't followed.
+pub fn functions(entries: &[Entry], blocks: &[Block]) -> Vec<Function> {
+    let by_start: BTreeMap<usize, &Block> = blocks.iter().map(|b| (b.start, b)).collect();
+    let mut starts: BTreeSet<usize> = entries
+        .iter()
+        .filter_map(|entry| match entry {
+            Entry::Code(inst) if inst.op == OpCodes::call => inst.target(),
+            _ => None,
+        })
+        .collect();
+    if let Some(first) = blocks.first() {
+        starts.insert(first.start);
+    }
+    starts.retain(|start| by_start.contains_key(start));
+
+    let mut retr = Vec::new();
+    for start in &starts {
+        let mut seen = BTreeSet::new();
+        let mut todo = vec![*start];
+        while let Some(addr) = todo.pop() {
+            let block = match by_start.get(&addr) {
+                Some(block) => block,
+                None => continue,
+            };
+            if !seen.insert(addr) {
+                continue;
+            }
+            for successor in &block.successors {
+                if !starts.contains(successor) {
+                    todo.push(*successor);
+                }
+            }
+        }
+        let end = seen.iter().map(|b| by_start[b].end).max().unwrap_or(*start);
+        retr.push(Function {
+            start: *start,
+            end,
+            blocks: seen.into_iter().collect(),
+        });
+    }
+    retr
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum XrefKind {
+    /// jmp, jt or jf
+    Jump,
+    Call,
+    /// rmem from a literal address
+    Read,
+    /// wmem to a literal address
+    Write,
+}
+
+impl fmt::Display for XrefKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            XrefKind::Jump => "jump",
+            XrefKind::Call => "call",
+            XrefKind::Read => "read",
+            XrefKind::Write => "write",
+        };
+        f.pad(name)
+    }
+}
+
+/// An instruction at `from` referring to address `to`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Xref {
+    pub from: usize,
+    pub to: usize,
+    pub kind: XrefKind,
+}
+
+/// Every literal jump and call target and every literal rmem and wmem
+/// address, in address order of the referring instruction
+pub fn xrefs(entries: &[Entry]) -> Vec<Xref> {
+    let mut retr = Vec::new();
+    for entry in entries {
+        let inst = match entry {
+            Entry::Code(inst) => inst,
+            Entry::Word(_, _) => continue,
+        };
+        for (role, operand) in inst.op.operands().iter().zip(&inst.operands) {
+            let kind = match role {
+                Role::JumpTarget if inst.op == OpCodes::call => XrefKind::Call,
+                Role::JumpTarget => XrefKind::Jump,
+                Role::MemoryRead => XrefKind::Read,
+                Role::MemoryWrite => XrefKind::Write,
+                _ => continue,
+            };
+            if let Some(to) = operand.literal() {
+                retr.push(Xref {
+                    from: inst.addr,
+                    to: to as usize,
+                    kind,
+                });
+            }
+        }
+    }
+    retr
+}
+
+/// Xrefs looked up by the address they refer to
+#[derive(Debug, Default)]
+pub struct XrefIndex {
+    by_target: BTreeMap<usize, Vec<Xref>>,
+}
+
+impl XrefIndex {
+    pub fn new(entries: &[Entry]) -> XrefIndex {
+        let mut index = XrefIndex::default();
+        for xref in xrefs(entries) {
+            index.by_target.entry(xref.to).or_default().push(xref);
+        }
+        index
+    }
+
+    /// References to `addr`, in address order of the referring instruction
+    pub fn to(&self, addr: usize) -> &[Xref] {
+        self.by_target
+            .get(&addr)
+            .map_or(&[], |refs| refs.as_slice())
+    }
+
+    /// References made by the instruction at `addr`
+    pub fn from(&self, addr: usize) -> Vec<&Xref> {
+        self.by_target
+            .values()
+            .flatten()
+            .filter(|xref| xref.from == addr)
+            .collect()
+    }
+
+    /// Every referenced address with its references, in address order
+    pub fn targets(&self) -> impl Iterator<Item = (usize, &[Xref])> {
+        self.by_target
+            .iter()
+            .map(|(addr, refs)| (*addr, refs.as_slice()))
+    }
+}
+
+/// Text printed by consecutive out instructions with literal operands
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Text {
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+/// The run of out with ASCII literals at the start of `entries`, one
+/// character per entry, None if it is shorter than two. The run stops before
+/// any later entry whose address `breaks` is true for, listings break it at
+/// labels.
+pub fn string_at(entries: &[Entry], breaks: impl Fn(usize) -> bool) -> Option<Text> {
+    let mut retr: Option<Text> = None;
+    for entry in entries {
+        let inst = match entry {
+            Entry::Code(inst) if inst.op == OpCodes::out => inst,
+            _ => break,
+        };
+        let value = match inst.operands[0].literal() {
+            Some(value) if value < 128 => value,
+            _ => break,
+        };
+        if retr.is_some() && breaks(inst.addr) {
+            break;
+        }
+        let text = retr.get_or_insert(Text {
+            start: inst.addr,
+            end: inst.addr,
+            text: String::new(),
+        });
+        text.text.push(value as u8 as char);
+        text.end = inst.next();
+    }
+    retr.filter(|t| t.text.len() > 1)
+}
+
+/// Runs of at least two ASCII characters
+pub fn strings(entries: &[Entry]) -> Vec<Text> {
+    let mut retr = Vec::new();
+    let mut idx = 0;
+    while idx < entries.len() {
+        match string_at(&entries[idx..], |_| false) {
+            Some(text) => {
+                idx += text.text.len();
+                retr.push(text);
+            }
+            None => idx += 1,
+        }
+    }
+    retr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decompile::disassemble;
+
+    #[test]
+    fn blocks() {
+        let data = [
+            1, 32768, 3, // 0: set reg0 3
+            9, 32768, 32768, 32767, // 3: add reg0 reg0 32767
+            17, 20, // 7: call 20
+            7, 32768, 3,  // 9: jt reg0 3
+            0,  // 12: halt
+            30, // 13: data
+            18, // 14: ret
+        ];
+        let listing = disassemble(&data, 0);
+        let blocks: Vec<(usize, usize, Vec<usize>)> = basic_blocks(&listing.entries)
+            .into_iter()
+            .map(|b| (b.start, b.end, b.successors))
+            .collect();
+        assert_eq!(
+            blocks,
+            vec![
+                (0, 3, vec![3]),
+                (3, 12, vec![3, 12]),
+                (12, 13, vec![]),
+                (14, 15, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn functions_xrefs_strings() {
+        let data = [
+            17, 7, // 0: call 7
+            19, 72, 19, 105, // 2: out H, 4: out i
+            0,   // 6: halt
+            8, 32768, 11, // 7: jf reg0 11
+            18, // 10: ret
+            18, // 11: ret
+        ];
+        let listing = disassemble(&data, 0);
+        let blocks = basic_blocks(&listing.entries);
+        let functions: Vec<(usize, usize, Vec<usize>)> = functions(&listing.entries, &blocks)
+            .into_iter()
+            .map(|f| (f.start, f.end, f.blocks))
+            .collect();
+        assert_eq!(functions, vec![(0, 7, vec![0]), (7, 12, vec![7, 10, 11])]);
+        assert_eq!(
+            xrefs(&listing.entries),
+            vec![
+                Xref {
+                    from: 0,
+                    to: 7,
+                    kind: XrefKind::Call
+                },
+                Xref {
+                    from: 7,
+                    to: 11,
+                    kind: XrefKind::Jump
+                },
+            ]
+        );
+        assert_eq!(
+            strings(&listing.entries),
+            vec![Text {
+                start: 2,
+                end: 6,
+                text: "Hi".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn xref_index() {
+        let data = [
+            15, 32768, 20, // 0: rmem reg0 20
+            16, 20, 32768, // 3: wmem 20 reg0
+            15, 32769, 32768, // 6: rmem reg1 reg0
+            6, 0, // 9: jmp 0
+            17, 0, // 11: call 0
+        ];
+        let index = XrefIndex::new(&disassemble(&data, 0).entries);
+        let refs: Vec<(usize, XrefKind)> = index.to(20).iter().map(|x| (x.from, x.kind)).collect();
+        assert_eq!(refs, vec![(0, XrefKind::Read), (3, XrefKind::Write)]);
+        let refs: Vec<(usize, XrefKind)> = index.to(0).iter().map(|x| (x.from, x.kind)).collect();
+        assert_eq!(refs, vec![(9, XrefKind::Jump), (11, XrefKind::Call)]);
+        assert!(index.to(6).is_empty());
+        assert_eq!(index.from(3).len(), 1);
+        assert_eq!(
+            index.targets().map(|(addr, _)| addr).collect::<Vec<_>>(),
+            vec![0, 20]
+        );
+    }
+}
@@ -0,0 +1,217 @@
+use crate::opcodes::{OpCodes, Role};
+
+// Assembles instructions in the syntax the disassembler prints:
+//   add reg0 reg1 4
+//   out A
+// Registers can also be written r0..r7, characters for out as 'A' or '\n'.
+// A bare operand of out is a character unless it is a number, `out 5`
+// writes the value 5; the digit is `out '5'`, as the disassembler prints it.
+// `.word 1 2 3` puts the words as they are.
+// Several instructions on one line are separated by ';', '#' starts a
+// comment.
+
+fn parse_char(text: &str) -> Option<u16> {
+    let inner = text.strip_prefix('\'')?.strip_suffix('\'')?;
+    match inner {
+        "\\n" => Some(10),
+        "\\t" => Some(9),
+        "\\'" => Some(39),
+        "\\\\" => Some(92),
+        " " => Some(32),
+        _ => {
+            let mut chars = inner.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if (c as u32) < 128 => Some(c as u16),
+                _ => None,
+            }
+        }
+    }
+}
+
+pub fn parse_operand(text: &str) -> Result<u16, String> {
+    if let Some(value) = parse_char(text) {
+        return Ok(value);
+    }
+    let reg = text
+        .strip_prefix("reg")
+        .or_else(|| text.strip_prefix('r'))
+        .and_then(|r| r.parse::<u16>().ok());
+    if let Some(reg) = reg {
+        return match reg {
+            0..=7 => Ok(32768 + reg),
+            _ => Err(format!("invalid register '{}'", text)),
+        };
+    }
+    match text.parse::<u16>() {
+        Ok(value) if value <= 32767 => Ok(value),
+        Ok(value) => Err(format!("literal {} out of range", value)),
+        Err(_) => Err(format!("invalid operand '{}'", text)),
+    }
+}
+
+/// Splits an instruction into words, keeping quoted characters together
+fn tokens(text: &str) -> Vec<String> {
+    let mut retr = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                quoted = !quoted;
+                current.push(c);
+            }
+            '\\' if quoted => {
+                current.push(c);
+                if let Some(next) = chars.next() {
+                    current.push(next);
+                }
+            }
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    retr.push(current.clone());
+                    current.clear();
+                }
+            }
+            _ => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        retr.push(current);
+    }
+    retr
+}
+
+/// Assembles one instruction
+pub fn assemble_instruction(text: &str) -> Result<Vec<u16>, String> {
+    let words = tokens(text);
+    let name = match words.first() {
+        Some(name) => name,
+        None => return Err("empty instruction".to_string()),
+    };
+    if name == ".word" {
+        // raw data, any 16 bit value
+        return words[1..]
+            .iter()
+            .map(|w| {
+                parse_operand(w).or_else(|_| {
+                    w.parse::<u16>()
+                        .map_err(|_| format!("invalid word '{}'", w))
+                })
+            })
+            .collect();
+    }
+    let op = OpCodes::from_name(name).ok_or(format!("unknown instruction '{}'", name))?;
+    let args = &words[1..];
+    if args.len() != op.argcount() {
+        return Err(format!(
+            "{} takes {} operands, got {}",
+            op,
+            op.argcount(),
+            args.len()
+        ));
+    }
+    let mut retr = vec![op.value()];
+    for (role, arg) in op.operands().iter().zip(args) {
+        let value = match (&op, parse_operand(arg)) {
+            (_, Ok(value)) => value,
+            // the disassembler prints out's operand as a bare character
+            (OpCodes::out, Err(_)) if arg.chars().count() == 1 => {
+                arg.chars().next().unwrap() as u16
+            }
+            (_, Err(what)) => return Err(what),
+        };
+        if *role == Role::Destination && value < 32768 {
+            return Err(format!("{} writes to a register, not '{}'", op, arg));
+        }
+        retr.push(value);
+    }
+    Ok(retr)
+}
+
+/// Splits a line at ';' and drops the '#' comment, quoted characters are
+/// operands and never split
+fn statements(line: &str) -> Vec<&str> {
+    let mut retr = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut chars = line.char_indices();
+    while let Some((pos, c)) = chars.next() {
+        match c {
+            '\'' => quoted = !quoted,
+            '\\' if quoted => {
+                chars.next();
+            }
+            ';' if !quoted => {
+                retr.push(&line[start..pos]);
+                start = pos + 1;
+            }
+            '#' if !quoted => {
+                retr.push(&line[start..pos]);
+                return retr;
+            }
+            _ => {}
+        }
+    }
+    retr.push(&line[start..]);
+    retr
+}
+
+/// Assembles a sequence of instructions separated by ';' or newlines
+pub fn assemble(text: &str) -> Result<Vec<u16>, String> {
+    let mut retr = Vec::new();
+    for line in text.lines() {
+        for inst in statements(line) {
+            if inst.trim().is_empty() {
+                continue;
+            }
+            let words = assemble_instruction(inst.trim())
+                .map_err(|e| format!("'{}': {}", inst.trim(), e))?;
+            retr.extend(words);
+        }
+    }
+    Ok(retr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decompile;
+
+    #[test]
+    fn roundtrip() {
+        let words =
+            assemble("add reg0 r1 4; out A\nout '\\n' # newline\njmp 7; mod r2 r2 3; in r0")
+                .unwrap();
+        assert_eq!(
+            words,
+            vec![9, 32768, 32769, 4, 19, 65, 19, 10, 6, 7, 11, 32770, 32770, 3, 20, 32768]
+        );
+        let text: Vec<String> = decompile::disassemble(&words[..6], 0)
+            .entries
+            .iter()
+            .map(|entry| entry.to_string())
+            .collect();
+        assert_eq!(text, vec!["0: add reg0 reg1 4", "4: out A"]);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(assemble("add reg0 1").is_err());
+        assert!(assemble("frob 1").is_err());
+        assert!(assemble("set reg8 1").is_err());
+        assert!(assemble("push 40000").is_err());
+        assert!(assemble("set 5 1").is_err());
+        assert_eq!(
+            assemble(".word 30 40000 r1").unwrap(),
+            vec![30, 40000, 32769]
+        );
+        assert!(assemble(".word x").is_err());
+        assert_eq!(assemble("out 5; out '5'").unwrap(), vec![19, 5, 19, 53]);
+        assert_eq!(assemble("out '#' # comment").unwrap(), vec![19, 35]);
+        assert_eq!(
+            assemble("out ';'; out '\\''").unwrap(),
+            vec![19, 59, 19, 39]
+        );
+    }
+}
@@ -1,137 +1,121 @@
 use std::fmt;
 use std::path;
 
-use crate::opcodes::OpCodes;
-use crate::program::Program;
+use crate::instruction::{sweep, DecodeError, Instruction, Operand};
+use crate::program::{LoadError, Program};
 
-fn parse_arg(value: u16) -> String {
-    match value {
-        0..=32767 => value.to_string(),
-        32768..=32775 => {
-            let reg = value - 32768;
-            format!("reg{}", reg)
-        }
-        _ => panic!("invalid value {}", value),
-    }
-}
+// Disassembly never fails, words that don't decode become .word entries and
+// the reason ends up in the diagnostics.
 
-#[derive(Debug, Clone)]
-pub struct OpData {
-    code: OpCodes,
-    idx: usize,
-    args: Vec<String>,
+#[derive(Debug, Clone, PartialEq)]
+pub enum Entry {
+    Code(Instruction),
+    /// a word that isn't the start of an instruction
+    Word(usize, u16),
 }
 
-impl OpData {
-    pub fn from(code: OpCodes, idx: usize) -> OpData {
-        OpData {
-            idx,
-            code,
-            args: Vec::new(),
-        }
-    }
-    pub fn argtext(&self) -> String {
-        let mut argtext = String::new();
-        if self.code == OpCodes::out {
-            for a in &self.args {
-                argtext += &a;
-            }
-        } else {
-            for a in &self.args {
-                argtext += &format!("{} ", a);
-            }
+impl Entry {
+    pub fn addr(&self) -> usize {
+        match self {
+            Entry::Code(inst) => inst.addr,
+            Entry::Word(addr, _) => *addr,
         }
-        argtext.trim().to_string()
     }
 }
 
-impl fmt::Display for OpData {
+impl fmt::Display for Entry {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}: {} {}", self.idx, self.code, self.argtext())
+        match self {
+            Entry::Code(inst) => write!(f, "{}", inst),
+            Entry::Word(addr, value) => write!(f, "{}: .word {}", addr, value),
+        }
     }
 }
 
-fn parse_op(code: &OpCodes, data: &[u16], idx: usize, argcount: usize) -> OpData {
-    let mut retr = OpData::from(code.clone(), idx);
-    if argcount == 0 {
-        return retr;
-    }
-    if idx + 1 >= data.len() {
-        retr.args.push("<arg0?>".to_string());
-        return retr;
-    }
-    match code {
-        OpCodes::out => retr.args.push(format!("{}", data[idx + 1] as u8 as char)),
-        _ => retr.args.push(parse_arg(data[idx + 1])),
-    };
-    if argcount == 1 {
-        return retr;
-    }
-    if idx + 2 >= data.len() {
-        retr.args.push("<arg1?>".to_string());
-        return retr;
-    }
-    retr.args.push(parse_arg(data[idx + 2]));
-    if argcount == 2 {
-        return retr;
-    }
-    retr.args.push(parse_arg(data[idx + 3]));
-    retr
+#[derive(Debug, Default)]
+pub struct Disassembly {
+    pub entries: Vec<Entry>,
+    /// everything that didn't decode cleanly, in address order
+    pub diagnostics: Vec<DecodeError>,
 }
 
-pub fn parse_data_offset(data: Vec<u16>, offset: usize) -> Vec<OpData> {
-    let mut retr = Vec::new();
-    let mut idx = 0;
-    while idx < data.len() {
-        let value = OpCodes::parse(data[idx]);
-        match value {
-            OpCodes::unknown(_) => idx += 1,
-            _ => {
-                retr.push(parse_op(&value, &data, idx + offset, value.argcount()));
-                idx += 1 + value.argcount();
+/// Linear sweep over `data`, which starts at address `offset`
+pub fn disassemble(data: &[u16], offset: usize) -> Disassembly {
+    let mut retr = Disassembly::default();
+    for result in sweep(data) {
+        match result {
+            Ok(mut inst) => {
+                inst.addr += offset;
+                for (index, operand) in inst.operands.iter().enumerate() {
+                    if let Operand::Invalid(word) = operand {
+                        retr.diagnostics.push(DecodeError::InvalidOperand {
+                            addr: inst.addr,
+                            index,
+                            word: *word,
+                        });
+                    }
+                }
+                retr.entries.push(Entry::Code(inst));
+            }
+            Err(error) => {
+                let addr = error.addr();
+                retr.diagnostics.push(error.offset(offset));
+                retr.entries.push(Entry::Word(addr + offset, data[addr]));
             }
         }
     }
     retr
 }
 
-pub fn parse_data(data: Vec<u16>) -> Vec<OpData> {
-    parse_data_offset(data, 0)
+pub fn disassemble_file(file: path::PathBuf) -> Result<Disassembly, LoadError> {
+    let program = Program::parse_file(file.as_path())?;
+    Ok(disassemble(&program.data, 0))
 }
 
-pub fn parse_file(file: path::PathBuf) -> Vec<OpData> {
-    let program = Program::parse_file(file.as_path());
-    parse_data(program.data)
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcodes::OpCodes;
 
-pub fn cleanup(input: Vec<OpData>) -> Vec<OpData> {
-    let mut output = Vec::new();
-    let mut idx = 0;
-    while idx < input.len() {
-        let curr = input[idx].clone();
-        if curr.code != OpCodes::out {
-            output.push(curr.clone());
-            idx += 1;
-            continue;
-        }
-        let mut newdata = OpData::from(curr.code, curr.idx);
-        let mut args = vec![curr.args[0].clone()];
-        let mut count = 1;
-        while idx + count < input.len() && input[idx + count].code == OpCodes::out {
-            args.push(input[idx + count].args[0].clone());
-            count += 1;
-        }
-        idx += count;
-        newdata.args = args;
-        output.push(newdata);
+    #[test]
+    fn parse_with_offset() {
+        let listing = disassemble(&[9, 32768, 32769, 4, 19, 65], 100);
+        assert_eq!(listing.entries[0].to_string(), "100: add reg0 reg1 4");
+        assert_eq!(listing.entries[1].to_string(), "104: out A");
     }
-    output
-}
 
-pub fn serialize(input: Vec<OpData>) -> String {
-    let mut retr = String::new();
-    for i in input {
-        retr += &format!("{}\n", i);
+    #[test]
+    fn bad_data_never_panics() {
+        // add with an invalid operand, unknown word, truncated jt
+        let listing = disassemble(&[9, 32768, 65535, 4, 30, 7, 32768], 10);
+        let text: Vec<String> = listing.entries.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            text,
+            vec![
+                "10: add reg0 <invalid 65535> 4",
+                "14: .word 30",
+                "15: .word 7",
+                "16: .word 32768"
+            ]
+        );
+        assert_eq!(
+            listing.diagnostics,
+            vec![
+                DecodeError::InvalidOperand {
+                    addr: 10,
+                    index: 1,
+                    word: 65535
+                },
+                DecodeError::UnknownOpcode { addr: 14, word: 30 },
+                DecodeError::Truncated {
+                    addr: 15,
+                    op: OpCodes::jt
+                },
+                DecodeError::UnknownOpcode {
+                    addr: 16,
+                    word: 32768
+                },
+            ]
+        );
     }
-    retr
 }
@@ -0,0 +1,203 @@
+use serde::{Deserialize, Serialize};
+
+use crate::analysis::{self, Block, Function, Text, Xref};
+use crate::decompile::Entry;
+use crate::instruction::Operand;
+
+// JSON export of a disassembly and what analysis finds in it. Fields keep
+// their order and lists are sorted by address; fields are only ever added,
+// anything else bumps VERSION.
+//
+// {
+//   "version": 1,
+//   "start": 0,                 first address covered
+//   "end": 7,                   address after the last word covered
+//   "instructions": [{
+//     "address": 0,
+//     "mnemonic": "jt",
+//     "opcode": 7,
+//     "operands": [{"kind": "register", "value": 0},
+//                  {"kind": "literal", "value": 5}],   kind is literal,
+//                                                      register or invalid
+//     "words": [7, 32768, 5]
+//   }],
+//   "data": [{"address": 6, "value": 40000}],    words that don't decode
+//   "blocks": [{"start": 0, "end": 3, "successors": [5, 3]}],
+//   "functions": [{"start": 0, "end": 5, "blocks": [0, 3]}],
+//   "xrefs": [{"from": 0, "to": 5, "kind": "jump"}],    kind is jump, call,
+//                                                   read or write
+//   "strings": [{"start": 9, "end": 13, "text": "Hi"}]
+// }
+//
+// Block, function and string ends are the address after their last
+// instruction.
+
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OperandKind {
+    Literal,
+    Register,
+    Invalid,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportOperand {
+    pub kind: OperandKind,
+    /// the literal, register number or invalid word
+    pub value: u16,
+}
+
+impl From<&Operand> for ExportOperand {
+    fn from(operand: &Operand) -> ExportOperand {
+        let (kind, value) = match operand {
+            Operand::Literal(value) => (OperandKind::Literal, *value),
+            Operand::Register(reg) => (OperandKind::Register, *reg as u16),
+            Operand::Invalid(word) => (OperandKind::Invalid, *word),
+        };
+        ExportOperand { kind, value }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportInstruction {
+    pub address: usize,
+    pub mnemonic: String,
+    pub opcode: u16,
+    pub operands: Vec<ExportOperand>,
+    pub words: Vec<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportWord {
+    pub address: usize,
+    pub value: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Export {
+    pub version: u32,
+    pub start: usize,
+    pub end: usize,
+    pub instructions: Vec<ExportInstruction>,
+    pub data: Vec<ExportWord>,
+    pub blocks: Vec<Block>,
+    pub functions: Vec<Function>,
+    pub xrefs: Vec<Xref>,
+    pub strings: Vec<Text>,
+}
+
+impl Export {
+    /// Runs the analyses over `entries`, which are in address order
+    pub fn new(entries: &[Entry]) -> Export {
+        let mut instructions = Vec::new();
+        let mut data = Vec::new();
+        for entry in entries {
+            match entry {
+                Entry::Code(inst) => instructions.push(ExportInstruction {
+                    address: inst.addr,
+                    mnemonic: inst.op.to_string(),
+                    opcode: inst.op.value(),
+                    operands: inst.operands.iter().map(ExportOperand::from).collect(),
+                    words: inst.words(),
+                }),
+                Entry::Word(address, value) => data.push(ExportWord {
+                    address: *address,
+                    value: *value,
+                }),
+            }
+        }
+        let start = entries.first().map_or(0, |e| e.addr());
+        let end = match entries.last() {
+            Some(Entry::Code(inst)) => inst.next(),
+            Some(Entry::Word(address, _)) => address + 1,
+            None => start,
+        };
+        let blocks = analysis::basic_blocks(entries);
+        Export {
+            version: VERSION,
+            start,
+            end,
+            instructions,
+            data,
+            functions: analysis::functions(entries, &blocks),
+            blocks,
+            xrefs: analysis::xrefs(entries),
+            strings: analysis::strings(entries),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn from_json(text: &str) -> Result<Export, String> {
+        serde_json::from_str(text).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decompile::disassemble;
+
+    #[test]
+    fn layout() {
+        // 0: jt reg0 5, 3: call 5, 5: ret, 6: 40000
+        let listing = disassemble(&[7, 32768, 5, 17, 5, 18, 40000], 0);
+        let export = Export::new(&listing.entries);
+        let json: serde_json::Value = serde_json::from_str(&export.to_json()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "version": 1,
+                "start": 0,
+                "end": 7,
+                "instructions": [
+                    {
+                        "address": 0,
+                        "mnemonic": "jt",
+                        "opcode": 7,
+                        "operands": [
+                            {"kind": "register", "value": 0},
+                            {"kind": "literal", "value": 5}
+                        ],
+                        "words": [7, 32768, 5]
+                    },
+                    {
+                        "address": 3,
+                        "mnemonic": "call",
+                        "opcode": 17,
+                        "operands": [{"kind": "literal", "value": 5}],
+                        "words": [17, 5]
+                    },
+                    {"address": 5, "mnemonic": "ret", "opcode": 18, "operands": [], "words": [18]}
+                ],
+                "data": [{"address": 6, "value": 40000}],
+                "blocks": [
+                    {"start": 0, "end": 3, "successors": [5, 3]},
+                    {"start": 3, "end": 5, "successors": [5]},
+                    {"start": 5, "end": 6, "successors": []}
+                ],
+                "functions": [
+                    {"start": 0, "end": 5, "blocks": [0, 3]},
+                    {"start": 5, "end": 6, "blocks": [5]}
+                ],
+                "xrefs": [
+                    {"from": 0, "to": 5, "kind": "jump"},
+                    {"from": 3, "to": 5, "kind": "call"}
+                ],
+                "strings": []
+            })
+        );
+        // field order is part of the layout
+        let text = export.to_json();
+        let keys: Vec<usize> = ["version", "start", "end", "instructions", "data", "blocks"]
+            .iter()
+            .map(|k| text.find(&format!("\"{}\"", k)).unwrap())
+            .collect();
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(Export::from_json(&text).unwrap(), export);
+    }
+}
@@ -0,0 +1,320 @@
+use std::error;
+use std::fmt;
+
+use crate::opcodes::{OpCodes, Role};
+
+// Decoded instructions with typed operands, for anything that wants to
+// look at the code rather than print it.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Literal(u16),
+    /// register number, 0..=7
+    Register(u8),
+    /// a word above 32775
+    Invalid(u16),
+}
+
+impl Operand {
+    pub fn from_word(word: u16) -> Operand {
+        match word {
+            0..=32767 => Operand::Literal(word),
+            32768..=32775 => Operand::Register((word - 32768) as u8),
+            _ => Operand::Invalid(word),
+        }
+    }
+
+    /// The word this operand is encoded as
+    pub fn word(&self) -> u16 {
+        match self {
+            Operand::Literal(value) | Operand::Invalid(value) => *value,
+            Operand::Register(reg) => 32768 + *reg as u16,
+        }
+    }
+
+    pub fn literal(&self) -> Option<u16> {
+        match self {
+            Operand::Literal(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Literal(value) => write!(f, "{}", value),
+            Operand::Register(reg) => write!(f, "reg{}", reg),
+            Operand::Invalid(value) => write!(f, "<invalid {}>", value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub addr: usize,
+    pub op: OpCodes,
+    pub operands: Vec<Operand>,
+}
+
+impl Instruction {
+    /// Number of words, opcode included
+    pub fn size(&self) -> usize {
+        1 + self.operands.len()
+    }
+
+    /// Address of the following instruction
+    pub fn next(&self) -> usize {
+        self.addr + self.size()
+    }
+
+    pub fn words(&self) -> Vec<u16> {
+        let mut retr = vec![self.op.value()];
+        retr.extend(self.operands.iter().map(|o| o.word()));
+        retr
+    }
+
+    /// The jump or call target when it is a literal
+    pub fn target(&self) -> Option<usize> {
+        let pos = self.op.operand(Role::JumpTarget)?;
+        self.operands.get(pos)?.literal().map(|t| t as usize)
+    }
+
+    /// Whether execution can go on to the next instruction, calls are
+    /// expected to return
+    pub fn falls_through(&self) -> bool {
+        !matches!(self.op, OpCodes::jmp | OpCodes::ret | OpCodes::halt)
+    }
+
+    pub fn has_invalid_operands(&self) -> bool {
+        self.operands
+            .iter()
+            .any(|o| matches!(o, Operand::Invalid(_)))
+    }
+
+    /// The instruction without its address, in the syntax the assembler
+    /// reads
+    pub fn assembly(&self) -> String {
+        let mut retr = self.op.to_string();
+        for operand in &self.operands {
+            let text = match (&self.op, operand.literal().and_then(out_char)) {
+                (OpCodes::out, Some(text)) => text,
+                _ => operand.to_string(),
+            };
+            retr += " ";
+            retr += &text;
+        }
+        retr
+    }
+}
+
+/// out's literal as the assembler reads it back. Digits would read as
+/// numbers and ';' splits instructions, so those stay quoted or numeric.
+pub(crate) fn out_char(value: u16) -> Option<String> {
+    match value {
+        10 => Some("'\\n'".to_string()),
+        9 => Some("'\\t'".to_string()),
+        39 => Some("'\\''".to_string()),
+        92 => Some("'\\\\'".to_string()),
+        59 => None,
+        32 | 35 | 48..=57 => Some(format!("'{}'", value as u8 as char)),
+        33..=126 => Some((value as u8 as char).to_string()),
+        _ => None,
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.addr, self.assembly())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    /// address is past the end of the data
+    OutOfBounds(usize),
+    UnknownOpcode {
+        addr: usize,
+        word: u16,
+    },
+    /// the data ends before all operands
+    Truncated {
+        addr: usize,
+        op: OpCodes,
+    },
+    /// an operand above 32775, decode() returns these as Operand::Invalid
+    InvalidOperand {
+        addr: usize,
+        index: usize,
+        word: u16,
+    },
+}
+
+impl DecodeError {
+    pub fn addr(&self) -> usize {
+        match self {
+            DecodeError::OutOfBounds(addr)
+            | DecodeError::UnknownOpcode { addr, .. }
+            | DecodeError::Truncated { addr, .. }
+            | DecodeError::InvalidOperand { addr, .. } => *addr,
+        }
+    }
+
+    /// The same error for data that starts at `offset`
+    pub fn offset(self, offset: usize) -> DecodeError {
+        match self {
+            DecodeError::OutOfBounds(addr) => DecodeError::OutOfBounds(addr + offset),
+            DecodeError::UnknownOpcode { addr, word } => DecodeError::UnknownOpcode {
+                addr: addr + offset,
+                word,
+            },
+            DecodeError::Truncated { addr, op } => DecodeError::Truncated {
+                addr: addr + offset,
+                op,
+            },
+            DecodeError::InvalidOperand { addr, index, word } => DecodeError::InvalidOperand {
+                addr: addr + offset,
+                index,
+                word,
+            },
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::OutOfBounds(addr) => write!(f, "{}: past the end of the data", addr),
+            DecodeError::UnknownOpcode { addr, word } => {
+                write!(f, "{}: unknown opcode {}", addr, word)
+            }
+            DecodeError::Truncated { addr, op } => {
+                write!(f, "{}: {} is missing operands", addr, op)
+            }
+            DecodeError::InvalidOperand { addr, index, word } => {
+                write!(f, "{}: operand {} is invalid ({})", addr, index, word)
+            }
+        }
+    }
+}
+
+impl error::Error for DecodeError {}
+
+/// Decodes the instruction at `addr`, operands above 32775 decode as
+/// Operand::Invalid
+pub fn decode(data: &[u16], addr: usize) -> Result<Instruction, DecodeError> {
+    let word = *data.get(addr).ok_or(DecodeError::OutOfBounds(addr))?;
+    let op = OpCodes::parse(word);
+    if op.info().is_none() {
+        return Err(DecodeError::UnknownOpcode { addr, word });
+    }
+    let end = addr + 1 + op.argcount();
+    if end > data.len() {
+        return Err(DecodeError::Truncated { addr, op });
+    }
+    let operands = data[addr + 1..end]
+        .iter()
+        .map(|w| Operand::from_word(*w))
+        .collect();
+    Ok(Instruction { addr, op, operands })
+}
+
+/// Linear sweep from the start of `data` to its end, see sweep
+pub struct Sweep<'a> {
+    data: &'a [u16],
+    addr: usize,
+}
+
+/// Decodes every instruction in `data` one after the other. A word that
+/// doesn't start an instruction comes out as an error and the sweep goes on
+/// with the next word.
+pub fn sweep(data: &[u16]) -> Sweep<'_> {
+    Sweep { data, addr: 0 }
+}
+
+impl Iterator for Sweep<'_> {
+    type Item = Result<Instruction, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.addr >= self.data.len() {
+            return None;
+        }
+        let result = decode(self.data, self.addr);
+        self.addr = match &result {
+            Ok(inst) => inst.next(),
+            Err(_) => self.addr + 1,
+        };
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble;
+
+    #[test]
+    fn sweep_skips_bad_words() {
+        // 0: out A, 2: unknown 30, 3: nop, 4: truncated jt
+        let addresses: Vec<Result<usize, usize>> = sweep(&[19, 65, 30, 21, 7])
+            .map(|r| r.map(|inst| inst.addr).map_err(|e| e.addr()))
+            .collect();
+        assert_eq!(addresses, vec![Ok(0), Err(2), Ok(3), Err(4)]);
+    }
+
+    #[test]
+    fn printable_out_roundtrips() {
+        for value in 32..=126 {
+            let out = Instruction {
+                addr: 0,
+                op: OpCodes::out,
+                operands: vec![Operand::Literal(value)],
+            };
+            let text = out.assembly();
+            assert_eq!(
+                assemble::assemble_instruction(&text).unwrap(),
+                out.words(),
+                "{}",
+                text
+            );
+            assert_eq!(assemble::assemble(&text).unwrap(), out.words(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn decode_instructions() {
+        let data = [9, 32768, 40000, 4, 19, 10, 30, 3];
+        let add = decode(&data, 0).unwrap();
+        assert_eq!(
+            add.operands,
+            vec![
+                Operand::Register(0),
+                Operand::Invalid(40000),
+                Operand::Literal(4)
+            ]
+        );
+        assert_eq!(add.next(), 4);
+        assert_eq!(add.words(), data[..4].to_vec());
+        assert_eq!(add.to_string(), "0: add reg0 <invalid 40000> 4");
+
+        let out = decode(&data, 4).unwrap();
+        assert_eq!(out.to_string(), "4: out '\\n'");
+        assert_eq!(
+            assemble::assemble_instruction("out '\\n'").unwrap(),
+            out.words()
+        );
+
+        assert_eq!(
+            decode(&data, 6),
+            Err(DecodeError::UnknownOpcode { addr: 6, word: 30 })
+        );
+        assert_eq!(
+            decode(&data, 7),
+            Err(DecodeError::Truncated {
+                addr: 7,
+                op: OpCodes::pop
+            })
+        );
+        assert_eq!(decode(&data, 8), Err(DecodeError::OutOfBounds(8)));
+    }
+}
@@ -1,6 +1,13 @@
+pub mod analysis;
+pub mod assemble;
 pub mod decompile;
+pub mod export;
+pub mod instruction;
+pub mod listing;
 pub mod opcodes;
 pub mod program;
+pub mod trace;
+pub mod validate;
 
 #[cfg(test)]
 mod tests {
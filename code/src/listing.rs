@@ -0,0 +1,372 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use crate::analysis;
+use crate::decompile::Entry;
+use crate::instruction::{out_char, Instruction, Operand};
+use crate::opcodes::{OpCodes, Role};
+
+// Formatting options for disassembly. The defaults print what
+// Instruction's Display does.
+//
+// Labels replace jump targets and memory addresses and are printed on a
+// line of their own before the address they name. Label files have one
+// label per line, address then name:
+//   # comment
+//   2125 print_string
+//   6027 teleporter
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegisterNames {
+    /// reg0
+    Reg,
+    /// r0
+    R,
+    /// $0
+    Dollar,
+}
+
+impl RegisterNames {
+    pub fn from_name(name: &str) -> Option<RegisterNames> {
+        match name {
+            "reg" => Some(RegisterNames::Reg),
+            "r" => Some(RegisterNames::R),
+            "$" => Some(RegisterNames::Dollar),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self, reg: u8) -> String {
+        match self {
+            RegisterNames::Reg => format!("reg{}", reg),
+            RegisterNames::R => format!("r{}", reg),
+            RegisterNames::Dollar => format!("${}", reg),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// addresses and literals in hex
+    pub hex: bool,
+    /// the encoded words between address and mnemonic
+    pub raw: bool,
+    pub registers: RegisterNames,
+    /// comment literals in the printable range with their character
+    pub chars: bool,
+    pub labels: BTreeMap<usize, String>,
+    /// print runs of out as one string
+    pub strings: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            hex: false,
+            raw: false,
+            registers: RegisterNames::Reg,
+            chars: false,
+            labels: BTreeMap::new(),
+            strings: false,
+        }
+    }
+}
+
+/// The command line flags Options::parse_flag takes
+pub const FLAGS: &str = "[--hex] [--raw] [--regs reg|r|$] [--chars] [--labels FILE]";
+
+/// Parses a label file
+pub fn parse_labels(text: &str) -> Result<BTreeMap<usize, String>, String> {
+    let mut labels = BTreeMap::new();
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let address = fields.next().and_then(|a| a.parse::<usize>().ok());
+        match (address, fields.next(), fields.next()) {
+            (Some(address), Some(name), None) => {
+                labels.insert(address, name.to_string());
+            }
+            _ => return Err(format!("line {}: expected ADDRESS NAME", idx + 1)),
+        }
+    }
+    Ok(labels)
+}
+
+/// Labels for every literal call and jump target, fn_ADDR for calls and
+/// L_ADDR for jumps
+pub fn auto_labels(entries: &[Entry]) -> BTreeMap<usize, String> {
+    let mut labels = BTreeMap::new();
+    for entry in entries {
+        let inst = match entry {
+            Entry::Code(inst) => inst,
+            Entry::Word(_, _) => continue,
+        };
+        for (role, operand) in inst.op.operands().iter().zip(&inst.operands) {
+            if let (Role::JumpTarget, Operand::Literal(target)) = (role, operand) {
+                let target = *target as usize;
+                let name = match inst.op {
+                    OpCodes::call => format!("fn_{}", target),
+                    _ => format!("L_{}", target),
+                };
+                // a call target stays a function when something also jumps there
+                if inst.op == OpCodes::call || !labels.contains_key(&target) {
+                    labels.insert(target, name);
+                }
+            }
+        }
+    }
+    labels
+}
+
+fn printable(value: u16) -> bool {
+    (32..127).contains(&value)
+}
+
+impl Options {
+    /// Applies a formatting flag from the command line, taking its value
+    /// from `rest`. Ok(false) if `flag` isn't one of FLAGS.
+    pub fn parse_flag<'a>(
+        &mut self,
+        flag: &str,
+        rest: &mut impl Iterator<Item = &'a String>,
+    ) -> Result<bool, String> {
+        match flag {
+            "--hex" => self.hex = true,
+            "--raw" => self.raw = true,
+            "--chars" => self.chars = true,
+            "--regs" => {
+                self.registers = rest
+                    .next()
+                    .and_then(|name| RegisterNames::from_name(name))
+                    .ok_or(format!("{} needs one of reg, r, $", flag))?
+            }
+            "--labels" => {
+                let path = rest.next().ok_or(format!("{} needs a file", flag))?;
+                let text = fs::read_to_string(path)
+                    .map_err(|e| format!("Failed to read {} : {}", path, e))?;
+                let labels = parse_labels(&text).map_err(|e| format!("{}: {}", path, e))?;
+                self.labels.extend(labels);
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    pub fn address(&self, address: usize) -> String {
+        if self.hex {
+            format!("{:04x}", address)
+        } else {
+            address.to_string()
+        }
+    }
+
+    pub fn number(&self, value: u16) -> String {
+        if self.hex {
+            format!("0x{:x}", value)
+        } else {
+            value.to_string()
+        }
+    }
+
+    pub fn operand(&self, op: &OpCodes, role: Role, operand: &Operand) -> String {
+        let value = match operand {
+            Operand::Register(reg) => return self.registers.name(*reg),
+            Operand::Invalid(word) => return format!("<invalid {}>", word),
+            Operand::Literal(value) => *value,
+        };
+        match role {
+            Role::JumpTarget | Role::MemoryRead | Role::MemoryWrite => {
+                if let Some(label) = self.labels.get(&(value as usize)) {
+                    return label.clone();
+                }
+            }
+            _ if *op == OpCodes::out => {
+                if let Some(text) = out_char(value) {
+                    return text;
+                }
+            }
+            _ => (),
+        }
+        self.number(value)
+    }
+
+    fn raw_words(&self, words: &[u16]) -> String {
+        let mut text: Vec<String> = words
+            .iter()
+            .map(|w| {
+                if self.hex {
+                    format!("{:04x}", w)
+                } else {
+                    format!("{:5}", w)
+                }
+            })
+            .collect();
+        // longest instruction is 4 words
+        let width = if self.hex { 4 } else { 5 };
+        text.resize(4, " ".repeat(width));
+        text.join(" ")
+    }
+
+    /// The instruction without address or raw words
+    pub fn code(&self, inst: &Instruction) -> String {
+        let mut retr = inst.op.to_string();
+        let mut chars = Vec::new();
+        for (role, operand) in inst.op.operands().iter().zip(&inst.operands) {
+            retr += " ";
+            retr += &self.operand(&inst.op, *role, operand);
+            match (role, operand) {
+                (Role::Source, Operand::Literal(value))
+                    if inst.op != OpCodes::out && printable(*value) =>
+                {
+                    chars.push(format!("'{}'", *value as u8 as char))
+                }
+                _ => (),
+            }
+        }
+        if self.chars && !chars.is_empty() {
+            retr += &format!("  # {}", chars.join(" "));
+        }
+        retr
+    }
+
+    pub fn instruction(&self, inst: &Instruction) -> String {
+        let mut retr = format!("{}: ", self.address(inst.addr));
+        if self.raw {
+            retr += &self.raw_words(&inst.words());
+            retr += "  ";
+        }
+        retr + &self.code(inst)
+    }
+
+    pub fn entry(&self, entry: &Entry) -> String {
+        match entry {
+            Entry::Code(inst) => self.instruction(inst),
+            Entry::Word(addr, value) => {
+                let mut retr = format!("{}: ", self.address(*addr));
+                if self.raw {
+                    retr += &self.raw_words(&[*value]);
+                    retr += "  ";
+                }
+                retr += &format!(".word {}", self.number(*value));
+                if self.chars && printable(*value) {
+                    retr += &format!("  # '{}'", *value as u8 as char);
+                }
+                retr
+            }
+        }
+    }
+
+    /// One line per entry with label lines in between
+    pub fn listing(&self, entries: &[Entry]) -> String {
+        let mut retr = String::new();
+        let mut idx = 0;
+        while idx < entries.len() {
+            let entry = &entries[idx];
+            if let Some(label) = self.labels.get(&entry.addr()) {
+                retr += &format!("{}:\n", label);
+            }
+            let text = self.string_at(&entries[idx..]);
+            match text {
+                Some((text, count)) => {
+                    retr += &format!("{}: out \"{}\"\n", self.address(entry.addr()), text);
+                    idx += count;
+                }
+                None => {
+                    retr += &self.entry(entry);
+                    retr += "\n";
+                    idx += 1;
+                }
+            }
+        }
+        retr
+    }
+
+    /// With `strings`, the text of a run of out with literals starting at
+    /// the first entry, and how many entries it spans
+    fn string_at(&self, entries: &[Entry]) -> Option<(String, usize)> {
+        if !self.strings || self.raw {
+            return None;
+        }
+        // labels start a new line
+        let text = analysis::string_at(entries, |addr| self.labels.contains_key(&addr))?;
+        Some((text.text.escape_default().to_string(), text.text.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decompile::disassemble;
+
+    // 0: set reg1 65, 3: jt reg1 9, 6: call 9, 8: 40000, 9: out H, 11: out i
+    const DATA: [u16; 13] = [1, 32769, 65, 7, 32769, 9, 17, 9, 40000, 19, 72, 19, 105];
+
+    fn lines(options: &Options) -> Vec<String> {
+        let listing = disassemble(&DATA, 0);
+        options
+            .listing(&listing.entries)
+            .lines()
+            .map(|l| l.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn default_matches_display() {
+        let listing = disassemble(&DATA, 0);
+        let expected: Vec<String> = listing.entries.iter().map(|e| e.to_string()).collect();
+        assert_eq!(lines(&Options::default()), expected);
+    }
+
+    #[test]
+    fn options() {
+        let mut options = Options {
+            hex: true,
+            registers: RegisterNames::R,
+            chars: true,
+            strings: true,
+            ..Options::default()
+        };
+        options.labels = auto_labels(&disassemble(&DATA, 0).entries);
+        assert_eq!(
+            lines(&options),
+            vec![
+                "0000: set r1 0x41  # 'A'",
+                "0003: jt r1 fn_9",
+                "0006: call fn_9",
+                "0008: .word 0x9c40",
+                "fn_9:",
+                "0009: out \"Hi\"",
+            ]
+        );
+
+        let raw = Options {
+            raw: true,
+            registers: RegisterNames::Dollar,
+            ..Options::default()
+        };
+        assert_eq!(lines(&raw)[0], "0:     1 32769    65        set $1 65");
+    }
+
+    #[test]
+    fn label_file() {
+        let labels = parse_labels("# labels\n2125 print\n\n6027 teleporter\n").unwrap();
+        assert_eq!(labels.get(&2125).map(|l| l.as_str()), Some("print"));
+        assert!(parse_labels("print 2125").is_err());
+    }
+
+    #[test]
+    fn flags() {
+        let mut options = Options::default();
+        let args: Vec<String> = vec!["--regs".to_string(), "$".to_string()];
+        let mut rest = args.iter();
+        while let Some(arg) = rest.next() {
+            assert!(options.parse_flag(arg, &mut rest).unwrap());
+        }
+        assert_eq!(options.registers, RegisterNames::Dollar);
+        assert!(!options.parse_flag("--from", &mut rest).unwrap());
+        assert!(options.parse_flag("--regs", &mut rest).is_err());
+    }
+}
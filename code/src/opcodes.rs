@@ -1,7 +1,80 @@
 use std::fmt;
 
-// number of arguments for all except unknown
-// word value for unknown
+// OPCODES describes every instruction, OpCodes is the decoded form.
+// Unknown opcodes carry the word they were decoded from and have no
+// operands.
+
+/// What an instruction does with an operand
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    /// register the result is written to
+    Destination,
+    /// value that is read, a literal or a register
+    Source,
+    /// address execution continues at
+    JumpTarget,
+    /// address in memory that is read
+    MemoryRead,
+    /// address in memory that is written
+    MemoryWrite,
+}
+
+#[derive(Debug)]
+pub struct OpInfo {
+    pub op: OpCodes,
+    pub code: u16,
+    pub mnemonic: &'static str,
+    pub operands: &'static [Role],
+}
+
+impl OpInfo {
+    pub fn argcount(&self) -> usize {
+        self.operands.len()
+    }
+
+    /// Index of the first operand with `role`
+    pub fn operand(&self, role: Role) -> Option<usize> {
+        self.operands.iter().position(|r| *r == role)
+    }
+}
+
+use Role::*;
+
+const fn info(op: OpCodes, code: u16, mnemonic: &'static str, operands: &'static [Role]) -> OpInfo {
+    OpInfo {
+        op,
+        code,
+        mnemonic,
+        operands,
+    }
+}
+
+/// Indexed by opcode
+#[rustfmt::skip]
+pub const OPCODES: [OpInfo; 22] = [
+    info(OpCodes::halt, 0, "halt", &[]),
+    info(OpCodes::set,  1, "set",  &[Destination, Source]),
+    info(OpCodes::push, 2, "push", &[Source]),
+    info(OpCodes::pop,  3, "pop",  &[Destination]),
+    info(OpCodes::eq,   4, "eq",   &[Destination, Source, Source]),
+    info(OpCodes::gt,   5, "gt",   &[Destination, Source, Source]),
+    info(OpCodes::jmp,  6, "jmp",  &[JumpTarget]),
+    info(OpCodes::jt,   7, "jt",   &[Source, JumpTarget]),
+    info(OpCodes::jf,   8, "jf",   &[Source, JumpTarget]),
+    info(OpCodes::add,  9, "add",  &[Destination, Source, Source]),
+    info(OpCodes::mult, 10, "mult", &[Destination, Source, Source]),
+    info(OpCodes::mod_, 11, "mod",  &[Destination, Source, Source]),
+    info(OpCodes::and,  12, "and",  &[Destination, Source, Source]),
+    info(OpCodes::or,   13, "or",   &[Destination, Source, Source]),
+    info(OpCodes::not,  14, "not",  &[Destination, Source]),
+    info(OpCodes::rmem, 15, "rmem", &[Destination, MemoryRead]),
+    info(OpCodes::wmem, 16, "wmem", &[MemoryWrite, Source]),
+    info(OpCodes::call, 17, "call", &[JumpTarget]),
+    info(OpCodes::ret,  18, "ret",  &[]),
+    info(OpCodes::out,  19, "out",  &[Source]),
+    info(OpCodes::in_,  20, "in",   &[Destination]),
+    info(OpCodes::nop,  21, "nop",  &[]),
+];
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Clone, PartialEq)]
@@ -33,63 +106,78 @@ pub enum OpCodes {
 
 impl OpCodes {
     pub fn parse(val: u16) -> OpCodes {
-        match val {
-            0 => OpCodes::halt,
-            1 => OpCodes::set,
-            2 => OpCodes::push,
-            3 => OpCodes::pop,
-            4 => OpCodes::eq,
-            5 => OpCodes::gt,
-            6 => OpCodes::jmp,
-            7 => OpCodes::jt,
-            8 => OpCodes::jf,
-            9 => OpCodes::add,
-            10 => OpCodes::mult,
-            11 => OpCodes::mod_,
-            12 => OpCodes::and,
-            13 => OpCodes::or,
-            14 => OpCodes::not,
-            15 => OpCodes::rmem,
-            16 => OpCodes::wmem,
-            17 => OpCodes::call,
-            18 => OpCodes::ret,
-            19 => OpCodes::out,
-            20 => OpCodes::in_,
-            21 => OpCodes::nop,
-            _ => OpCodes::unknown(val),
+        match OPCODES.get(val as usize) {
+            Some(info) => info.op.clone(),
+            None => OpCodes::unknown(val),
         }
     }
-    pub fn argcount(&self) -> usize {
+
+    /// Looks up a mnemonic, `mod_` and `in_` work as well as `mod` and `in`
+    pub fn from_name(name: &str) -> Option<OpCodes> {
+        let name = name.strip_suffix('_').unwrap_or(name);
+        OPCODES
+            .iter()
+            .find(|info| info.mnemonic == name)
+            .map(|info| info.op.clone())
+    }
+
+    /// The table entry, None for unknown opcodes
+    pub fn info(&self) -> Option<&'static OpInfo> {
+        OPCODES.iter().find(|info| info.op == *self)
+    }
+
+    /// The word this opcode is encoded as
+    pub fn value(&self) -> u16 {
         match self {
-            OpCodes::halt => 0,
-            OpCodes::set => 2,
-            OpCodes::push => 1,
-            OpCodes::pop => 1,
-            OpCodes::eq => 3,
-            OpCodes::gt => 3,
-            OpCodes::jmp => 1,
-            OpCodes::jt => 2,
-            OpCodes::jf => 2,
-            OpCodes::add => 3,
-            OpCodes::mult => 3,
-            OpCodes::mod_ => 3,
-            OpCodes::and => 3,
-            OpCodes::or => 3,
-            OpCodes::not => 2,
-            OpCodes::rmem => 2,
-            OpCodes::wmem => 2,
-            OpCodes::call => 1,
-            OpCodes::ret => 0,
-            OpCodes::out => 1,
-            OpCodes::in_ => 1,
-            OpCodes::nop => 0,
-            _ => panic!("argcount on unknown opcode"),
+            OpCodes::unknown(val) => *val,
+            _ => self.info().map_or(0, |info| info.code),
         }
     }
+
+    pub fn operands(&self) -> &'static [Role] {
+        self.info().map_or(&[], |info| info.operands)
+    }
+
+    /// 0 for unknown opcodes, they are a single word of data
+    pub fn argcount(&self) -> usize {
+        self.operands().len()
+    }
+
+    /// Index of the first operand with `role`
+    pub fn operand(&self, role: Role) -> Option<usize> {
+        self.info()?.operand(role)
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        self.info().map_or("unknown", |info| info.mnemonic)
+    }
 }
 
 impl fmt::Display for OpCodes {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            OpCodes::unknown(val) => write!(f, "unknown({})", val),
+            _ => write!(f, "{}", self.mnemonic()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table() {
+        for (code, info) in OPCODES.iter().enumerate() {
+            assert_eq!(info.code as usize, code);
+            assert_eq!(OpCodes::parse(info.code), info.op);
+            assert_eq!(info.op.value(), info.code);
+            assert_eq!(OpCodes::from_name(info.mnemonic), Some(info.op.clone()));
+        }
+        assert_eq!(OpCodes::from_name("mod_"), Some(OpCodes::mod_));
+        assert_eq!(OpCodes::in_.to_string(), "in");
+        assert_eq!(OpCodes::unknown(99).argcount(), 0);
+        assert_eq!(OpCodes::unknown(99).to_string(), "unknown(99)");
+        assert_eq!(OpCodes::rmem.info().unwrap().operand(MemoryRead), Some(1));
     }
 }
@@ -1,11 +1,161 @@
 use std::io::prelude::*;
 
+use std::error;
+use std::fmt;
 use std::fs;
+use std::io;
 use std::path;
 
+// Programs are loaded from
+// - binaries, little endian 16 bit words
+// - hex dumps as written by xxd: "00000000: 1500 1500 1300 5700  ......W."
+// - comma separated lists of words, the binpacker input: "21, 21, 19, 87".
+//   Words may also be separated by whitespace, but without a single comma
+//   the text is taken as a binary: "12" is the word 12849.
+// A path of "-" reads stdin.
+
+/// Largest valid word, 32768..32775 are the registers
+pub const MAX_WORD: u16 = 32775;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Binary,
+    HexDump,
+    CommaList,
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(path::PathBuf, io::Error),
+    /// binaries need an even number of bytes
+    OddLength(usize),
+    InvalidWord {
+        address: usize,
+        value: u16,
+    },
+    Parse {
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(path, what) => write!(f, "Failed to read {} : {}", path.display(), what),
+            LoadError::OddLength(len) => write!(f, "odd number of bytes ({})", len),
+            LoadError::InvalidWord { address, value } => {
+                write!(f, "invalid word {} at {}", value, address)
+            }
+            LoadError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl error::Error for LoadError {}
+
+impl Format {
+    /// Guesses the format from the contents, anything that isn't a hex dump
+    /// or a list with at least one comma is a binary
+    pub fn detect(bytes: &[u8]) -> Format {
+        let text = match std::str::from_utf8(bytes) {
+            Ok(text) if !text.trim().is_empty() => text,
+            _ => return Format::Binary,
+        };
+        let printable = text.chars().all(|c| !c.is_control() || c.is_whitespace());
+        if !printable {
+            return Format::Binary;
+        }
+        let first = text.trim_start().lines().next().unwrap_or("");
+        let is_offset = |o: &str| !o.is_empty() && o.chars().all(|c| c.is_ascii_hexdigit());
+        match first.split_once(':') {
+            Some((offset, _)) if is_offset(offset) => Format::HexDump,
+            _ if text.contains(',')
+                && text
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || c == ',' || c.is_whitespace()) =>
+            {
+                Format::CommaList
+            }
+            _ => Format::Binary,
+        }
+    }
+}
+
+fn words_from_bytes(bytes: &[u8], strict: bool) -> Result<(Vec<u16>, Vec<String>), LoadError> {
+    let mut warnings = Vec::new();
+    if bytes.len() % 2 == 1 {
+        if strict {
+            return Err(LoadError::OddLength(bytes.len()));
+        }
+        warnings.push(format!(
+            "odd number of bytes ({}), the last byte is padded with 0",
+            bytes.len()
+        ));
+    }
+    let data = bytes
+        .chunks(2)
+        .map(|pair| u16::from_le_bytes([pair[0], *pair.get(1).unwrap_or(&0)]))
+        .collect();
+    Ok((data, warnings))
+}
+
+fn parse_error(line: usize, message: String) -> LoadError {
+    LoadError::Parse { line, message }
+}
+
+fn bytes_from_hexdump(text: &str) -> Result<Vec<u8>, LoadError> {
+    let mut bytes = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (offset, rest) = line
+            .split_once(':')
+            .ok_or_else(|| parse_error(idx + 1, "missing offset".to_string()))?;
+        let offset = usize::from_str_radix(offset.trim(), 16)
+            .map_err(|e| parse_error(idx + 1, format!("invalid offset: {}", e)))?;
+        if offset != bytes.len() {
+            return Err(parse_error(
+                idx + 1,
+                format!("offset {:x}, expected {:x}", offset, bytes.len()),
+            ));
+        }
+        // the ascii column is separated by two spaces
+        let hex = rest.trim_start().split("  ").next().unwrap_or("");
+        for group in hex.split_whitespace() {
+            if group.len() % 2 == 1 {
+                return Err(parse_error(idx + 1, format!("invalid hex '{}'", group)));
+            }
+            for pos in (0..group.len()).step_by(2) {
+                let byte = u8::from_str_radix(&group[pos..pos + 2], 16)
+                    .map_err(|e| parse_error(idx + 1, format!("invalid hex '{}': {}", group, e)))?;
+                bytes.push(byte);
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+fn words_from_list(text: &str) -> Result<Vec<u16>, LoadError> {
+    let mut data = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        let words = line.split(|c: char| c == ',' || c.is_whitespace());
+        for word in words.filter(|w| !w.is_empty()) {
+            let value = word
+                .parse::<u16>()
+                .map_err(|e| parse_error(idx + 1, format!("invalid word '{}': {}", word, e)))?;
+            data.push(value);
+        }
+    }
+    Ok(data)
+}
+
 pub struct Program {
     pub data: Vec<u16>,
     pub path: path::PathBuf,
+    /// problems found while loading
+    pub warnings: Vec<String>,
 }
 
 impl Program {
@@ -13,29 +163,71 @@ impl Program {
         Program {
             data: Vec::new(),
             path: path::PathBuf::new(),
+            warnings: Vec::new(),
         }
     }
-    pub fn parse_file(path: &path::Path) -> Program {
-        let mut file = match fs::File::open(path) {
-            Err(why) => panic!("Failed to open {} : {}", path.display(), why),
-            Ok(file) => file,
-        };
+
+    /// Loads a program, guessing the format. Problems that don't stop the
+    /// program from loading end up in `warnings`.
+    pub fn parse_file(path: &path::Path) -> Result<Program, LoadError> {
+        Program::load(path, None, false)
+    }
+
+    /// With `strict` odd length binaries and words above MAX_WORD are errors
+    pub fn load(
+        path: &path::Path,
+        format: Option<Format>,
+        strict: bool,
+    ) -> Result<Program, LoadError> {
         let mut buffer = Vec::new();
-        match file.read_to_end(&mut buffer) {
-            Err(why) => panic!("Failed to read {} : {}", path.display(), why),
-            Ok(_) => {}
+        let result = if path == path::Path::new("-") {
+            io::stdin().read_to_end(&mut buffer)
+        } else {
+            fs::File::open(path).and_then(|mut file| file.read_to_end(&mut buffer))
+        };
+        if let Err(why) = result {
+            return Err(LoadError::Io(path::PathBuf::from(path), why));
         }
+        let mut program = Program::parse(&buffer, format, strict)?;
+        program.path = path::PathBuf::from(path);
+        Ok(program)
+    }
 
-        let mut data: Vec<u16> = Vec::new();
-        for pair in buffer.chunks(2) {
-            let mut val: u16 = pair[0] as u16;
-            val += (pair[1] as u16) << 8;
-            data.push(val)
+    pub fn parse(bytes: &[u8], format: Option<Format>, strict: bool) -> Result<Program, LoadError> {
+        let format = format.unwrap_or_else(|| Format::detect(bytes));
+        let text = || String::from_utf8_lossy(bytes);
+        let (data, mut warnings) = match format {
+            Format::Binary => words_from_bytes(bytes, strict)?,
+            Format::HexDump => words_from_bytes(&bytes_from_hexdump(&text())?, strict)?,
+            Format::CommaList => (words_from_list(&text())?, Vec::new()),
+        };
+        for (address, value) in data.iter().enumerate() {
+            if *value > MAX_WORD {
+                if strict {
+                    return Err(LoadError::InvalidWord {
+                        address,
+                        value: *value,
+                    });
+                }
+                warnings.push(format!("invalid word {} at {}", value, address));
+            }
         }
-        Program {
+        Ok(Program {
             data,
-            path: path::PathBuf::from(path),
-        }
+            path: path::PathBuf::new(),
+            warnings,
+        })
+    }
+
+    /// The little endian file contents for data
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.data.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+}
+
+impl Default for Program {
+    fn default() -> Program {
+        Program::new()
     }
 }
 
@@ -44,6 +236,58 @@ impl Clone for Program {
         Program {
             data: self.data.clone(),
             path: path::PathBuf::from(&self.path),
+            warnings: self.warnings.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats() {
+        let binary = [0x15, 0x00, 0x13, 0x00, 0x57, 0x00];
+        let hexdump = "00000000: 1500 1300 5700                      ....W.\n";
+        let list = "21, 19\n87\n";
+        assert_eq!(Format::detect(&binary), Format::Binary);
+        assert_eq!(Format::detect(hexdump.as_bytes()), Format::HexDump);
+        assert_eq!(Format::detect(list.as_bytes()), Format::CommaList);
+        for input in [&binary[..], hexdump.as_bytes(), list.as_bytes()].iter() {
+            let program = Program::parse(input, None, true).unwrap();
+            assert_eq!(program.data, vec![21, 19, 87]);
         }
+        // digits without a comma are too short to be anything but a binary
+        assert_eq!(Format::detect(b"12"), Format::Binary);
+        assert_eq!(Program::parse(b"12", None, true).unwrap().data, vec![12849]);
+        assert_eq!(Format::detect(b"21 19\n"), Format::Binary);
+    }
+
+    #[test]
+    fn odd_length_and_invalid_words() {
+        let odd = [0x15, 0x00, 0x13];
+        let program = Program::parse(&odd, Some(Format::Binary), false).unwrap();
+        assert_eq!(program.data, vec![21, 19]);
+        assert_eq!(program.warnings.len(), 1);
+        assert!(matches!(
+            Program::parse(&odd, Some(Format::Binary), true),
+            Err(LoadError::OddLength(3))
+        ));
+
+        let invalid = [0x15, 0x00, 0xff, 0xff];
+        assert_eq!(
+            Program::parse(&invalid, None, false)
+                .unwrap()
+                .warnings
+                .len(),
+            1
+        );
+        assert!(matches!(
+            Program::parse(&invalid, None, true),
+            Err(LoadError::InvalidWord {
+                address: 1,
+                value: 65535
+            })
+        ));
     }
 }
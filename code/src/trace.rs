@@ -0,0 +1,256 @@
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+
+use crate::opcodes::OpCodes;
+
+// Execution trace records, one per executed instruction.
+//
+// Text traces have one record per line, fields separated by " | ":
+//   count | ip | op operands | resolved values | writes
+//   120 | 2125 | add reg0 reg1 5 | 3 3 5 | reg0=8
+// Binary traces start with BINARY_MAGIC followed by records of little endian
+// values: u64 count, u16 ip, u16 opcode, u8 operand count, the raw operands,
+// the resolved values, u8 write count and (u16 target, u16 value) pairs.
+
+pub const BINARY_MAGIC: &[u8; 8] = b"SYNTRC02";
+
+/// A value written by an instruction, targets use the same encoding as
+/// operands so 32768..32775 are registers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceWrite {
+    pub target: u16,
+    pub value: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    pub count: u64,
+    pub ip: u16,
+    pub opcode: u16,
+    pub operands: Vec<u16>,
+    pub values: Vec<u16>,
+    pub writes: Vec<TraceWrite>,
+}
+
+fn format_operand(value: u16) -> String {
+    match value {
+        32768..=32775 => format!("reg{}", value - 32768),
+        _ => value.to_string(),
+    }
+}
+
+fn parse_operand(text: &str) -> Result<u16, String> {
+    match text.strip_prefix("reg") {
+        Some(reg) => match reg.parse::<u16>() {
+            Ok(reg) if reg < 8 => Ok(32768 + reg),
+            _ => Err(format!("invalid register '{}'", text)),
+        },
+        None => text
+            .parse::<u16>()
+            .map_err(|e| format!("invalid value '{}': {}", text, e)),
+    }
+}
+
+fn parse_opcode(text: &str) -> Result<u16, String> {
+    if let Some(op) = OpCodes::from_name(text) {
+        return Ok(op.value());
+    }
+    match text
+        .strip_prefix("unknown(")
+        .and_then(|t| t.strip_suffix(')'))
+    {
+        Some(code) => parse_operand(code),
+        None => Err(format!("unknown opcode '{}'", text)),
+    }
+}
+
+fn parse_list(text: &str) -> Result<Vec<u16>, String> {
+    text.split_whitespace().map(parse_operand).collect()
+}
+
+impl TraceRecord {
+    pub fn op(&self) -> OpCodes {
+        OpCodes::parse(self.opcode)
+    }
+
+    pub fn parse_text(line: &str) -> Result<TraceRecord, String> {
+        let fields: Vec<&str> = line.split('|').map(|f| f.trim()).collect();
+        if fields.len() != 5 {
+            return Err(format!("malformed trace line '{}'", line));
+        }
+        let count = fields[0]
+            .parse::<u64>()
+            .map_err(|e| format!("invalid count '{}': {}", fields[0], e))?;
+        let ip = parse_operand(fields[1])?;
+        let mut inst = fields[2].split_whitespace();
+        let opcode = parse_opcode(inst.next().unwrap_or(""))?;
+        let operands = inst.map(parse_operand).collect::<Result<Vec<u16>, String>>()?;
+        let values = parse_list(fields[3])?;
+        let mut writes = Vec::new();
+        for write in fields[4].split_whitespace() {
+            let mut parts = write.splitn(2, '=');
+            let target = parse_operand(parts.next().unwrap_or(""))?;
+            let value = parse_operand(parts.next().unwrap_or(""))?;
+            writes.push(TraceWrite { target, value });
+        }
+        Ok(TraceRecord {
+            count,
+            ip,
+            opcode,
+            operands,
+            values,
+            writes,
+        })
+    }
+
+    pub fn write_binary<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(&self.count.to_le_bytes())?;
+        out.write_all(&self.ip.to_le_bytes())?;
+        out.write_all(&self.opcode.to_le_bytes())?;
+        out.write_all(&[self.operands.len() as u8])?;
+        for operand in self.operands.iter().chain(self.values.iter()) {
+            out.write_all(&operand.to_le_bytes())?;
+        }
+        out.write_all(&[self.writes.len() as u8])?;
+        for write in &self.writes {
+            out.write_all(&write.target.to_le_bytes())?;
+            out.write_all(&write.value.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Reads the next binary record, None at end of input
+    pub fn read_binary<R: Read>(input: &mut R) -> io::Result<Option<TraceRecord>> {
+        let mut count = [0u8; 8];
+        match input.read_exact(&mut count) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let ip = read_u16(input)?;
+        let opcode = read_u16(input)?;
+        let operand_count = read_u8(input)?;
+        let operands = (0..operand_count)
+            .map(|_| read_u16(input))
+            .collect::<io::Result<Vec<u16>>>()?;
+        let values = (0..operand_count)
+            .map(|_| read_u16(input))
+            .collect::<io::Result<Vec<u16>>>()?;
+        let write_count = read_u8(input)?;
+        let mut writes = Vec::new();
+        for _ in 0..write_count {
+            let target = read_u16(input)?;
+            let value = read_u16(input)?;
+            writes.push(TraceWrite { target, value });
+        }
+        Ok(Some(TraceRecord {
+            count: u64::from_le_bytes(count),
+            ip,
+            opcode,
+            operands,
+            values,
+            writes,
+        }))
+    }
+}
+
+fn read_u8<R: Read>(input: &mut R) -> io::Result<u8> {
+    let mut buffer = [0u8; 1];
+    input.read_exact(&mut buffer)?;
+    Ok(buffer[0])
+}
+
+fn read_u16<R: Read>(input: &mut R) -> io::Result<u16> {
+    let mut buffer = [0u8; 2];
+    input.read_exact(&mut buffer)?;
+    Ok(u16::from_le_bytes(buffer))
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let operands: Vec<String> = self.operands.iter().map(|o| format_operand(*o)).collect();
+        let values: Vec<String> = self.values.iter().map(|v| v.to_string()).collect();
+        let writes: Vec<String> = self
+            .writes
+            .iter()
+            .map(|w| format!("{}={}", format_operand(w.target), w.value))
+            .collect();
+        let mut inst = self.op().to_string();
+        if !operands.is_empty() {
+            inst += " ";
+            inst += &operands.join(" ");
+        }
+        write!(
+            f,
+            "{} | {} | {} | {} | {}",
+            self.count,
+            self.ip,
+            inst,
+            values.join(" "),
+            writes.join(" ")
+        )
+    }
+}
+
+/// Reads a whole trace file, detecting text or binary from the header
+pub fn read_trace<R: BufRead>(input: &mut R) -> Result<Vec<TraceRecord>, String> {
+    let is_binary = input
+        .fill_buf()
+        .map_err(|e| e.to_string())?
+        .starts_with(BINARY_MAGIC);
+    let mut records = Vec::new();
+    if is_binary {
+        input.consume(BINARY_MAGIC.len());
+        while let Some(record) = TraceRecord::read_binary(input).map_err(|e| e.to_string())? {
+            records.push(record);
+        }
+    } else {
+        for line in input.lines() {
+            let line = line.map_err(|e| e.to_string())?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            records.push(TraceRecord::parse_text(&line)?);
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> TraceRecord {
+        TraceRecord {
+            count: 120,
+            ip: 2125,
+            opcode: 9,
+            operands: vec![32768, 32769, 5],
+            values: vec![3, 3, 5],
+            writes: vec![TraceWrite {
+                target: 32768,
+                value: 8,
+            }],
+        }
+    }
+
+    #[test]
+    fn text_roundtrip() {
+        let text = record().to_string();
+        assert_eq!(text, "120 | 2125 | add reg0 reg1 5 | 3 3 5 | reg0=8");
+        assert_eq!(TraceRecord::parse_text(&text).unwrap(), record());
+
+        let ret = "7 | 30 | ret |  | ";
+        assert_eq!(TraceRecord::parse_text(ret).unwrap().op(), OpCodes::ret);
+    }
+
+    #[test]
+    fn binary_roundtrip() {
+        let mut data = BINARY_MAGIC.to_vec();
+        record().write_binary(&mut data).unwrap();
+        record().write_binary(&mut data).unwrap();
+        let records = read_trace(&mut &data[..]).unwrap();
+        assert_eq!(records, vec![record(), record()]);
+    }
+}
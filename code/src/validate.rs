@@ -0,0 +1,229 @@
+use std::fmt;
+
+use crate::instruction::{sweep, DecodeError, Operand};
+use crate::opcodes::{OpCodes, Role};
+
+// Static checks on a program, decoded linearly from address 0 the same way
+// the disassembler does. Data mixed in with the code decodes as nonsense
+// instructions too, which is why findings that are often caused by that
+// (writes to literals, jumps into the middle of an instruction, odd out
+// arguments) are only warnings.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Severity {
+    pub fn from_name(name: &str) -> Option<Severity> {
+        match name {
+            "info" => Some(Severity::Info),
+            "warning" => Some(Severity::Warning),
+            "error" => Some(Severity::Error),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Check {
+    /// operand above 32775
+    InvalidWord,
+    /// instruction that writes to a literal instead of a register, only a
+    /// warning as a linear sweep runs into those in data
+    LiteralDestination,
+    /// jump target past the end of the image
+    JumpOutside,
+    /// jump target that isn't the start of an instruction
+    JumpMisaligned,
+    /// out with a literal that isn't printable ascii
+    NonPrintableOut,
+    /// last instruction runs past the end of the image
+    Truncated,
+}
+
+impl Check {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Check::InvalidWord => "invalid-word",
+            Check::LiteralDestination => "literal-destination",
+            Check::JumpOutside => "jump-outside",
+            Check::JumpMisaligned => "jump-misaligned",
+            Check::NonPrintableOut => "non-printable-out",
+            Check::Truncated => "truncated",
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        match self {
+            Check::InvalidWord | Check::JumpOutside => Severity::Error,
+            Check::LiteralDestination | Check::JumpMisaligned | Check::NonPrintableOut => {
+                Severity::Warning
+            }
+            Check::Truncated => Severity::Info,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub address: usize,
+    pub check: Check,
+    pub message: String,
+}
+
+impl Finding {
+    pub fn severity(&self) -> Severity {
+        self.check.severity()
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} {}: {}",
+            self.address,
+            self.severity(),
+            self.check.name(),
+            self.message
+        )
+    }
+}
+
+fn printable(value: u16) -> bool {
+    value == 10 || (32..127).contains(&value)
+}
+
+pub fn validate(data: &[u16]) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let mut report = |address, check, message| {
+        findings.push(Finding {
+            address,
+            check,
+            message,
+        })
+    };
+    let mut decoded = Vec::new();
+    for result in sweep(data) {
+        match result {
+            Ok(inst) => decoded.push(inst),
+            Err(DecodeError::Truncated { addr, op }) => {
+                report(
+                    addr,
+                    Check::Truncated,
+                    format!("{} needs {} operands, image ends", op, op.argcount()),
+                );
+                break;
+            }
+            Err(_) => {}
+        }
+    }
+    let mut starts = vec![false; data.len()];
+    for inst in &decoded {
+        starts[inst.addr] = true;
+    }
+
+    for inst in decoded {
+        let (address, op) = (inst.addr, &inst.op);
+        for (pos, (role, operand)) in op.operands().iter().zip(&inst.operands).enumerate() {
+            let value = match operand {
+                Operand::Invalid(value) => {
+                    report(
+                        address,
+                        Check::InvalidWord,
+                        format!("{} operand {} is {}", op, pos, value),
+                    );
+                    continue;
+                }
+                // registers are only known at run time
+                Operand::Register(_) => continue,
+                Operand::Literal(value) => *value,
+            };
+            match role {
+                Role::Destination => report(
+                    address,
+                    Check::LiteralDestination,
+                    format!("{} writes to literal {}", op, value),
+                ),
+                Role::JumpTarget if value as usize >= data.len() => report(
+                    address,
+                    Check::JumpOutside,
+                    format!("{} to {}, the image ends at {}", op, value, data.len()),
+                ),
+                Role::JumpTarget if !starts[value as usize] => report(
+                    address,
+                    Check::JumpMisaligned,
+                    format!("{} to {}, not the start of an instruction", op, value),
+                ),
+                Role::Source if *op == OpCodes::out && !printable(value) => report(
+                    address,
+                    Check::NonPrintableOut,
+                    format!("out {} is not printable", value),
+                ),
+                _ => (),
+            }
+        }
+    }
+    findings.sort_by_key(|f| f.address);
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checks(data: &[u16]) -> Vec<(usize, Check)> {
+        validate(data)
+            .iter()
+            .map(|f| (f.address, f.check))
+            .collect()
+    }
+
+    #[test]
+    fn clean_program() {
+        // set reg0 65, out reg0, jt reg0 0, halt
+        assert!(validate(&[1, 32768, 65, 19, 32768, 7, 32768, 0, 0]).is_empty());
+    }
+
+    #[test]
+    fn findings() {
+        let data = [
+            1, 5, 1, // 0: set 5 1
+            9, 32768, 40000, 1, // 3: add reg0 40000 1
+            6, 100, // 7: jmp 100
+            6, 1, // 9: jmp 1
+            19, 7, // 11: out 7
+            16, 5, 1,  // 13: wmem 5 1
+            17, // 16: call ...
+        ];
+        assert_eq!(
+            checks(&data),
+            vec![
+                (0, Check::LiteralDestination),
+                (3, Check::InvalidWord),
+                (7, Check::JumpOutside),
+                (9, Check::JumpMisaligned),
+                (11, Check::NonPrintableOut),
+                (16, Check::Truncated),
+            ]
+        );
+        assert_eq!(
+            validate(&data)[0].to_string(),
+            "0: warning literal-destination: set writes to literal 5"
+        );
+    }
+}
@@ -1,37 +1,49 @@
+use std::env;
 use std::io;
 use std::io::prelude::*;
+use std::path;
 
-use std::net::{Shutdown, TcpStream};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
 
-use code::decompile;
+use code::analysis::XrefIndex;
+use code::assemble;
+use code::decompile::{self, Entry};
+use code::instruction::DecodeError;
+use code::listing;
 use messages::command::Command;
-use messages::{Message, ResponseData, VmState};
+use messages::{Endpoint, Message, ResponseData, VmState, DEFAULT_PORT};
 
-fn get_line() -> std::io::Result<String> {
-    print!("> ");
+/// Connection to the debugserver
+trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+/// Reads a line from the user, None at end of input
+fn get_line(prompt: &str) -> std::io::Result<Option<String>> {
+    print!("{}> ", prompt);
     io::stdout().flush()?;
     let mut line = String::new();
-    io::stdin().read_line(&mut line)?;
-    Ok(String::from(line.trim()))
+    if io::stdin().read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(String::from(line.trim())))
 }
 
-fn send(cmd: Command, stream: &mut TcpStream) -> std::io::Result<()> {
+fn send(cmd: Command, stream: &mut dyn Stream) -> std::io::Result<()> {
     let req = Message::Request(cmd);
     let data = req.serialize();
     let len: u64 = data.len() as u64;
-    stream.write(&len.to_le_bytes())?;
-    stream.write(&data)?;
+    stream.write_all(&len.to_le_bytes())?;
+    stream.write_all(&data)?;
     stream.flush()
 }
 
-fn recv_response(stream: &mut TcpStream) -> std::io::Result<Vec<ResponseData>> {
-    // length
+fn recv_response(stream: &mut dyn Stream) -> std::io::Result<Vec<ResponseData>> {
+    // length, a closed connection shows up as UnexpectedEof
     let mut buffer = [0; 8];
-    let numbytes = stream.take(8).read(&mut buffer)?;
-    if numbytes == 0 {
-        return Ok(vec![ResponseData::Empty]);
-    }
-    assert_eq!(numbytes, 8);
+    stream.read_exact(&mut buffer)?;
     let len = u64::from_le_bytes(buffer);
 
     // data
@@ -45,9 +57,19 @@ fn recv_response(stream: &mut TcpStream) -> std::io::Result<Vec<ResponseData>> {
     }
 }
 
-fn print_state(state: VmState) {
-    let opdata = decompile::parse_data(Vec::from(state.here));
-    let optext = decompile::serialize(opdata);
+fn print_state(state: VmState, format: &listing::Options) {
+    let listing = decompile::disassemble(&state.here, state.ip);
+    // the last instruction is usually cut off
+    let end = listing
+        .diagnostics
+        .iter()
+        .find_map(|d| match d {
+            DecodeError::Truncated { addr, .. } => Some(*addr),
+            _ => None,
+        })
+        .unwrap_or(usize::MAX);
+    let entries: Vec<Entry> = listing.entries.into_iter().filter(|e| e.addr() < end).collect();
+    let optext = format.listing(&entries);
     let regtext : Vec<String> = state.registers.iter().enumerate().map(|(idx, regval)| format!("r{}:{}",idx, regval)).collect();
     println!("{}/{}, regs {}\n{}", state.ip, state.count, regtext.join(", "), optext);
 }
@@ -57,57 +79,146 @@ fn print_dump(address : usize, data : Vec<u16>) {
     println!("{:?}", data);
 }
 
-fn handle_response(data: ResponseData) {
+fn handle_response(data: ResponseData, format: &listing::Options) {
     match data {
         ResponseData::Empty => {},
         ResponseData::Text(content) => println!("{}", content),
-        ResponseData::State(state) => print_state(state),
+        ResponseData::State(state) => print_state(state, format),
         ResponseData::Dump(address, data) => print_dump(address, data),
-        _ => panic!("no response {:?}", data),
     }
 }
-fn handle_quit(stream: &mut TcpStream) -> std::io::Result<bool> {
+fn handle_quit(stream: &mut dyn Stream) -> std::io::Result<bool> {
+    // the server drops the session, leaving the vm paused for the next client
     send(Command::Quit, stream)?;
-    stream.shutdown(Shutdown::Both)?;
     Ok(true)
 }
 
 fn handle_default(
     cmd: Command,
-    stream: &mut TcpStream,
+    stream: &mut dyn Stream,
     response_count: usize,
+    format: &listing::Options,
 ) -> std::io::Result<bool> {
     send(cmd, stream)?;
     for _ in 0..response_count {
         for r in recv_response(stream)? {
-            handle_response(r);
+            handle_response(r, format);
+        }
+    }
+    Ok(false)
+}
+
+/// Sends a memory command and returns the words in the dump it answers with
+fn memory(
+    cmd: Command,
+    stream: &mut dyn Stream,
+    format: &listing::Options,
+) -> std::io::Result<Vec<u16>> {
+    send(cmd, stream)?;
+    for r in recv_response(stream)? {
+        match r {
+            ResponseData::Dump(_, data) => return Ok(data),
+            other => handle_response(other, format),
+        }
+    }
+    Ok(Vec::new())
+}
+
+fn print_listing(marker: &str, data: &[u16], address: usize, format: &listing::Options) {
+    for entry in decompile::disassemble(data, address).entries {
+        println!("{} {}", marker, format.entry(&entry));
+    }
+}
+
+/// Reads instructions until an empty line and writes them to memory from
+/// `address` on, showing the code before and after each change
+fn handle_assemble(
+    address: usize,
+    stream: &mut dyn Stream,
+    format: &listing::Options,
+) -> std::io::Result<bool> {
+    println!("assembling at {}, empty line to finish", address);
+    let mut address = address;
+    while let Some(line) = get_line(&address.to_string())? {
+        if line.is_empty() {
+            break;
+        }
+        let words = match assemble::assemble(&line) {
+            Ok(words) => words,
+            Err(what) => {
+                println!("{}", what);
+                continue;
+            }
+        };
+        let before = memory(Command::PrintMemory(address, words.len()), stream, format)?;
+        let after = memory(Command::SetMemory(address, words.clone()), stream, format)?;
+        print_listing("-", &before, address, format);
+        print_listing("+", &after, address, format);
+        address += words.len();
+    }
+    Ok(false)
+}
+
+/// Lists the instructions that jump to, call, read or write `address`,
+/// from a linear disassembly of the whole memory
+fn handle_xrefs(
+    address: usize,
+    stream: &mut dyn Stream,
+    format: &listing::Options,
+) -> std::io::Result<bool> {
+    let data = memory(Command::PrintMemory(0, 32768), stream, format)?;
+    let listing = decompile::disassemble(&data, 0);
+    let index = XrefIndex::new(&listing.entries);
+    let refs = index.to(address);
+    println!("{} references to {}", refs.len(), format.address(address));
+    for xref in refs {
+        let entry = listing.entries.iter().find(|e| e.addr() == xref.from);
+        if let Some(entry) = entry {
+            println!("  {:5} {}", xref.kind, format.entry(entry));
         }
     }
     Ok(false)
 }
 
-fn handle(cmd: Command, stream: &mut TcpStream) -> std::io::Result<bool> {
+fn handle(
+    cmd: Command,
+    stream: &mut dyn Stream,
+    format: &listing::Options,
+) -> std::io::Result<bool> {
     match cmd {
         Command::Quit => handle_quit(stream),
-        Command::Run => handle_default(cmd, stream, 2),
-        Command::Step => handle_default(cmd, stream, 1),
-        Command::Continue => handle_default(cmd, stream, 2),
-        Command::AddBreakpoint(_) => handle_default(cmd, stream, 1),
-        Command::RemoveBreakpoint(_) => handle_default(cmd, stream, 1),
-        Command::PrintRegister(_) => handle_default(cmd, stream, 1),
-        Command::PrintMemory(_, _) => handle_default(cmd, stream, 1),
-        _ => panic!("unknown command {:?}", cmd),
+        Command::Run => handle_default(cmd, stream, 2, format),
+        Command::Step => handle_default(cmd, stream, 1, format),
+        Command::StepCount(_) => handle_default(cmd, stream, 1, format),
+        Command::Next => handle_default(cmd, stream, 2, format),
+        Command::Finish => handle_default(cmd, stream, 2, format),
+        Command::Until(_) => handle_default(cmd, stream, 2, format),
+        Command::Continue => handle_default(cmd, stream, 2, format),
+        Command::AddBreakpoint(_) => handle_default(cmd, stream, 1, format),
+        Command::RemoveBreakpoint(_) => handle_default(cmd, stream, 1, format),
+        Command::PrintRegister(_) => handle_default(cmd, stream, 1, format),
+        Command::PrintMemory(_, _) => handle_default(cmd, stream, 1, format),
+        Command::SetMemory(_, _) => handle_default(cmd, stream, 1, format),
+        Command::Assemble(address) => handle_assemble(address, stream, format),
+        Command::Xrefs(address) => handle_xrefs(address, stream, format),
+        Command::None => Ok(false),
     }
 }
 
-fn run(stream: &mut TcpStream) -> std::io::Result<()> {
+fn run(stream: &mut dyn Stream, format: &listing::Options) -> std::io::Result<()> {
     let mut buffer = [0; 1024];
-    stream.read(&mut buffer)?;
-    let greeting = String::from_utf8_lossy(&buffer[..]);
+    let numbytes = stream.read(&mut buffer)?;
+    let greeting = String::from_utf8_lossy(&buffer[..numbytes]);
     println!("{}", greeting);
     let mut last_line = String::new();
     loop {
-        let line = get_line()?;
+        let line = match get_line("")? {
+            Some(line) => line,
+            None => {
+                handle_quit(stream)?;
+                break;
+            }
+        };
         let run_line = match line.as_ref() {
             "" => last_line.clone(),
             _ => line,
@@ -115,29 +226,85 @@ fn run(stream: &mut TcpStream) -> std::io::Result<()> {
         match Command::parse(&run_line) {
             Ok(cmd) => {
                 last_line = run_line;
-                if let Ok(do_quit) = handle(cmd, stream) {
-                    if do_quit {
-                        break;
-                    }
+                if handle(cmd, stream, format)? {
+                    break;
                 }
-            },
+            }
             Err(what) => println!("{}", what),
         }
     }
     Ok(())
 }
 
+struct Config {
+    endpoint: Endpoint,
+    format: listing::Options,
+}
+
+fn value<'a>(arg: &str, args: &mut impl Iterator<Item = &'a String>) -> &'a String {
+    match args.next() {
+        Some(value) => value,
+        None => panic!("{} needs a value", arg),
+    }
+}
+
+impl Config {
+    fn new(args: &[String]) -> Config {
+        let mut host = String::from("localhost");
+        let mut port = DEFAULT_PORT;
+        let mut unix = None;
+        let mut format = listing::Options::default();
+        let mut rest = args[1..].iter();
+        while let Some(arg) = rest.next() {
+            match format.parse_flag(arg, &mut rest) {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(what) => panic!("{}", what),
+            }
+            match arg.as_ref() {
+                "--host" => host = value(arg, &mut rest).clone(),
+                "--port" => match value(arg, &mut rest).parse::<u16>() {
+                    Ok(p) => port = p,
+                    Err(what) => panic!("invalid port: {}", what),
+                },
+                "--unix" => unix = Some(path::PathBuf::from(value(arg, &mut rest))),
+                _ => panic!("unknown argument {}", arg),
+            }
+        }
+        let endpoint = match unix {
+            Some(path) => Endpoint::Unix(path),
+            None => Endpoint::tcp(&host, port),
+        };
+        Config { endpoint, format }
+    }
+}
+
+fn connect(endpoint: &Endpoint) -> std::io::Result<Box<dyn Stream>> {
+    match endpoint {
+        Endpoint::Tcp(address) => Ok(Box::new(TcpStream::connect(address)?)),
+        #[cfg(unix)]
+        Endpoint::Unix(path) => Ok(Box::new(UnixStream::connect(path)?)),
+        #[cfg(not(unix))]
+        Endpoint::Unix(_) => Err(io::Error::other("unix sockets are not supported")),
+    }
+}
+
 fn main() {
-    match TcpStream::connect("localhost:6565") {
+    let args: Vec<String> = env::args().collect();
+    let config = Config::new(&args);
+    match connect(&config.endpoint) {
         Ok(mut stream) => {
-            println!("connected");
-            match run(&mut stream) {
+            println!("connected to {}", config.endpoint);
+            match run(stream.as_mut(), &config.format) {
                 Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    println!("server closed the connection")
+                }
                 Err(e) => panic!("Error durring network {}", e),
             }
         }
         Err(e) => {
-            panic!("failed to connect: {}", e);
+            panic!("failed to connect to {}: {}", config.endpoint, e);
         }
     }
 }
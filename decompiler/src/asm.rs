@@ -0,0 +1,75 @@
+use code::assemble::assemble_instruction;
+use code::decompile::Entry;
+use code::listing;
+
+// Assembly the assembler reads back into the same words. Labels only show
+// up as comments, the assembler takes addresses. Instructions the assembler
+// refuses, like writes to a literal or invalid operands, are written as
+// .word with the instruction in a comment.
+
+pub fn assembly(entries: &[Entry], format: &listing::Options) -> String {
+    // hex and $0 registers wouldn't assemble
+    let plain = listing::Options::default();
+    let mut retr = String::new();
+    if let Some(first) = entries.first() {
+        retr += &format!("# from {}\n", first.addr());
+    }
+    for entry in entries {
+        if let Some(label) = format.labels.get(&entry.addr()) {
+            retr += &format!("# {}: {}\n", label, entry.addr());
+        }
+        match entry {
+            Entry::Code(inst) => {
+                let text = plain.code(inst);
+                if assemble_instruction(&text).as_ref() == Ok(&inst.words()) {
+                    retr += &text;
+                } else {
+                    let words: Vec<String> = inst.words().iter().map(|w| w.to_string()).collect();
+                    retr += &format!(".word {} # {}", words.join(" "), text);
+                }
+            }
+            Entry::Word(_, value) => retr += &format!(".word {}", value),
+        }
+        retr += "\n";
+    }
+    retr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use code::assemble::assemble;
+    use code::decompile::disassemble;
+    use code::program::Program;
+    use std::path::Path;
+
+    #[test]
+    fn roundtrip() {
+        let data = [
+            9, 32768, 32769, 4, 30, 19, 35, 19, 10, 6, 0, 40000, 19, 53, 19, 39, 19, 59, 7,
+        ];
+        let entries = disassemble(&data, 0).entries;
+        let mut format = listing::Options::default();
+        format.labels.insert(9, "loop".to_string());
+        let text = assembly(&entries, &format);
+        assert!(text.contains("# loop: 9\njmp 0\n"));
+        assert_eq!(assemble(&text).unwrap(), data.to_vec());
+    }
+
+    #[test]
+    fn unassemblable_instructions_are_words() {
+        // set 5 1, add reg0 <invalid 40000> 1
+        let data = [1, 5, 1, 9, 32768, 40000, 1];
+        let text = assembly(&disassemble(&data, 0).entries, &listing::Options::default());
+        assert!(text.contains(".word 1 5 1 # set 5 1\n"));
+        assert_eq!(assemble(&text).unwrap(), data.to_vec());
+    }
+
+    #[test]
+    fn challenge_roundtrip() {
+        let program = Program::parse_file(Path::new("../spec/challenge.bin")).unwrap();
+        let entries = disassemble(&program.data, 0).entries;
+        let text = assembly(&entries, &listing::Options::default());
+        assert_eq!(assemble(&text).unwrap(), program.data);
+    }
+}
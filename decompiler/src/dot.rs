@@ -0,0 +1,65 @@
+use std::collections::BTreeMap;
+
+use code::analysis::basic_blocks;
+use code::decompile::Entry;
+use code::listing;
+use code::opcodes::OpCodes;
+
+// Graphviz control flow graph, one node per basic block. Solid edges are
+// jumps and fall through, dashed edges are calls.
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+pub fn graph(entries: &[Entry], format: &listing::Options) -> String {
+    let blocks = basic_blocks(entries);
+    let by_address: BTreeMap<usize, &Entry> = entries.iter().map(|e| (e.addr(), e)).collect();
+    let mut retr = String::from("digraph program {\n    node [shape=box fontname=monospace];\n");
+    for block in &blocks {
+        let mut label = match format.labels.get(&block.start) {
+            Some(name) => format!("{}:\\l", escape(name)),
+            None => String::new(),
+        };
+        let mut calls = Vec::new();
+        for (_, entry) in by_address.range(block.start..block.end) {
+            label += &escape(&format.entry(entry));
+            label += "\\l";
+            if let Entry::Code(inst) = entry {
+                match inst.target() {
+                    Some(target) if inst.op == OpCodes::call => calls.push(target),
+                    _ => (),
+                }
+            }
+        }
+        retr += &format!("    b{} [label=\"{}\"];\n", block.start, label);
+        for successor in &block.successors {
+            if blocks.iter().any(|b| b.start == *successor) {
+                retr += &format!("    b{} -> b{};\n", block.start, successor);
+            }
+        }
+        for target in calls {
+            if blocks.iter().any(|b| b.start == target) {
+                retr += &format!("    b{} -> b{} [style=dashed];\n", block.start, target);
+            }
+        }
+    }
+    retr += "}\n";
+    retr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use code::decompile::disassemble;
+
+    #[test]
+    fn edges() {
+        // 0: jt r0 5, 3: call 5, 5: ret
+        let entries = disassemble(&[7, 32768, 5, 17, 5, 18], 0).entries;
+        let text = graph(&entries, &listing::Options::default());
+        assert!(text.contains("    b0 -> b5;\n    b0 -> b3;\n"));
+        assert!(text.contains("    b3 -> b5;\n    b3 -> b5 [style=dashed];\n"));
+        assert!(text.contains("b5 [label=\"5: ret\\l\"]"));
+    }
+}
@@ -1,51 +1,220 @@
 use std::env;
 
 use std::fs;
-use std::io::prelude::*;
 use std::path;
+use std::process;
 
-use code::decompile;
+use code::analysis::XrefIndex;
+use code::decompile::{self, Entry};
+use code::export::Export;
+use code::instruction::DecodeError;
+use code::listing;
+use code::program::Program;
 
-fn get_outpath(inpath: &str) -> path::PathBuf {
-    let inp = path::Path::new(inpath);
-    let inp = inp.canonicalize().unwrap();
-    let mut retr = path::PathBuf::from(inp);
-    retr.set_extension("decompiled");
-    retr
+mod asm;
+mod dot;
+mod pseudo;
+
+// Disassembles a program, or part of it, into one of several formats.
+// Output goes to stdout unless --output names a file, there is no
+// FILE.decompiled next to the input anymore.
+//
+// exit status: 0 decompiled, 1 decompiled but some instructions have
+// invalid operands or are cut off, 2 error
+
+const USAGE: &str = "usage: decompiler [--output FILE|-] [--from ADDR] [--to ADDR] \
+    [--format listing|json|asm|c|dot] [--auto-labels] [--xrefs] [--strict]";
+
+fn usage() -> String {
+    format!(
+        "{}\n  {} FILE\noutput goes to stdout unless --output is given, \
+         FILE.decompiled is no longer written",
+        USAGE,
+        listing::FLAGS
+    )
 }
 
-fn write(data: String, path: &path::Path) {
-    let mut file = match fs::File::create(path) {
-        Err(why) => panic!("Could not create file {} : {}", path.display(), why),
-        Ok(file) => file,
-    };
-    match file.write_all(data.as_bytes()) {
-        Err(why) => panic!("Failed to write file {}: {}", path.display(), why),
-        Ok(_) => println!("done"),
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Listing,
+    Json,
+    Assembly,
+    PseudoC,
+    Dot,
+}
+
+impl OutputFormat {
+    fn from_name(name: &str) -> Option<OutputFormat> {
+        match name {
+            "listing" => Some(OutputFormat::Listing),
+            "json" => Some(OutputFormat::Json),
+            "asm" => Some(OutputFormat::Assembly),
+            "c" => Some(OutputFormat::PseudoC),
+            "dot" => Some(OutputFormat::Dot),
+            _ => None,
+        }
     }
 }
 
-pub fn decompile_file(file: &str) {
-    let data = decompile::parse_file(path::PathBuf::from(file.clone()));
-    let clean_data = decompile::cleanup(data);
-    let text = decompile::serialize(clean_data);
+struct Config {
+    file: String,
+    /// None writes to stdout
+    output: Option<path::PathBuf>,
+    from: usize,
+    to: Option<usize>,
+    output_format: OutputFormat,
+    format: listing::Options,
+    auto_labels: bool,
+    /// append a cross reference section to the listing
+    xrefs: bool,
+    strict: bool,
+}
 
-    let outpath = get_outpath(file);
-    println!("writing to file {:?}", outpath);
+fn address(arg: &str, value: Option<&String>) -> Result<usize, String> {
+    value
+        .and_then(|v| v.parse::<usize>().ok())
+        .ok_or(format!("{} needs an address", arg))
+}
 
-    write(text, &outpath);
+impl Config {
+    fn new(args: &[String]) -> Result<Config, String> {
+        let mut files = Vec::new();
+        let mut config = Config {
+            file: String::new(),
+            output: None,
+            from: 0,
+            to: None,
+            output_format: OutputFormat::Listing,
+            format: listing::Options {
+                strings: true,
+                ..listing::Options::default()
+            },
+            auto_labels: false,
+            xrefs: false,
+            strict: false,
+        };
+        let mut rest = args[1..].iter();
+        while let Some(arg) = rest.next() {
+            if config.format.parse_flag(arg, &mut rest)? {
+                continue;
+            }
+            match arg.as_ref() {
+                "--output" | "-o" => match rest.next().map(|p| p.as_str()) {
+                    Some("-") => config.output = None,
+                    Some(path) => config.output = Some(path::PathBuf::from(path)),
+                    None => return Err(format!("{} needs a path", arg)),
+                },
+                "--from" => config.from = address(arg, rest.next())?,
+                "--to" => config.to = Some(address(arg, rest.next())?),
+                "--format" => {
+                    config.output_format = rest
+                        .next()
+                        .and_then(|name| OutputFormat::from_name(name))
+                        .ok_or(format!("{} needs one of listing, json, asm, c, dot", arg))?
+                }
+                "--auto-labels" => config.auto_labels = true,
+                "--xrefs" => config.xrefs = true,
+                "--strict" => config.strict = true,
+                "--help" | "-h" => return Err(usage()),
+                _ => files.push(arg.clone()),
+            }
+        }
+        if files.len() != 1 {
+            return Err(usage());
+        }
+        if config.xrefs && config.output_format != OutputFormat::Listing {
+            return Err("--xrefs only applies to listing output, json has them anyway".to_string());
+        }
+        config.file = files[0].clone();
+        Ok(config)
+    }
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    println!("args {:?}", args);
-    if args.len() < 2 {
-        println!("No input file specified");
-        return;
+/// Counts what didn't decode, listing the problems that aren't just data.
+/// Returns the number of those problems.
+fn report(diagnostics: &[DecodeError]) -> usize {
+    let mut unknown = 0;
+    for diagnostic in diagnostics {
+        match diagnostic {
+            DecodeError::UnknownOpcode { .. } => unknown += 1,
+            _ => eprintln!("{}", diagnostic),
+        }
+    }
+    eprintln!(
+        "{} words don't decode, {} other problems",
+        unknown,
+        diagnostics.len() - unknown
+    );
+    diagnostics.len() - unknown
+}
+
+/// Every referenced address with who refers to it:
+///   2125 fn_2125: call 1234, jump 1500
+fn xref_report(entries: &[Entry], format: &listing::Options) -> String {
+    let mut retr = String::from("\nxrefs:\n");
+    for (address, refs) in XrefIndex::new(entries).targets() {
+        retr += &format.address(address);
+        if let Some(label) = format.labels.get(&address) {
+            retr += &format!(" {}", label);
+        }
+        let refs: Vec<String> = refs
+            .iter()
+            .map(|xref| format!("{} {}", xref.kind, format.address(xref.from)))
+            .collect();
+        retr += &format!(": {}\n", refs.join(", "));
+    }
+    retr
+}
+
+fn decompile(config: &Config) -> Result<bool, String> {
+    let program = Program::load(path::Path::new(&config.file), None, config.strict)
+        .map_err(|e| format!("{}: {}", config.file, e))?;
+    for warning in &program.warnings {
+        eprintln!("{}: {}", config.file, warning);
+    }
+    let to = usize::min(config.to.unwrap_or(program.data.len()), program.data.len());
+    if config.from > to {
+        return Err(format!("--from {} is past --to {}", config.from, to));
+    }
+    let listing = decompile::disassemble(&program.data[config.from..to], config.from);
+    let problems = report(&listing.diagnostics);
+
+    let mut format = config.format.clone();
+    // gotos need somewhere to go
+    if config.auto_labels || config.output_format == OutputFormat::PseudoC {
+        // labels from a file win
+        for (address, label) in listing::auto_labels(&listing.entries) {
+            format.labels.entry(address).or_insert(label);
+        }
     }
+    let text = match config.output_format {
+        OutputFormat::Listing if config.xrefs => {
+            format.listing(&listing.entries) + &xref_report(&listing.entries, &format)
+        }
+        OutputFormat::Listing => format.listing(&listing.entries),
+        OutputFormat::Json => Export::new(&listing.entries).to_json() + "\n",
+        OutputFormat::Assembly => asm::assembly(&listing.entries, &format),
+        OutputFormat::PseudoC => pseudo::pseudo_c(&listing.entries, &format),
+        OutputFormat::Dot => dot::graph(&listing.entries, &format),
+    };
 
-    let file = &args[1];
-    println!("reading file {:}", file);
+    match &config.output {
+        Some(path) => fs::write(path, text)
+            .map_err(|e| format!("Failed to write {} : {}", path.display(), e))?,
+        None => print!("{}", text),
+    }
+    Ok(problems == 0)
+}
 
-    decompile_file(file);
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let result = Config::new(&args).and_then(|config| decompile(&config));
+    match result {
+        Ok(true) => process::exit(0),
+        Ok(false) => process::exit(1),
+        Err(what) => {
+            eprintln!("{}", what);
+            process::exit(2);
+        }
+    }
 }
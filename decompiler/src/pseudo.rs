@@ -0,0 +1,127 @@
+use code::analysis;
+use code::decompile::Entry;
+use code::instruction::{Instruction, Operand};
+use code::listing;
+use code::opcodes::OpCodes;
+
+// Pseudo C, one statement per instruction with gotos for jumps. Runs of
+// out become a single print.
+
+fn char_literal(value: u16) -> String {
+    match value {
+        0..=127 => format!("'{}'", (value as u8 as char).escape_default()),
+        _ => value.to_string(),
+    }
+}
+
+fn statement(inst: &Instruction, format: &listing::Options) -> String {
+    let operand = |idx: usize| -> String {
+        let role = inst.op.operands()[idx];
+        format.operand(&inst.op, role, &inst.operands[idx])
+    };
+    let target = |idx: usize| -> String {
+        match inst.operands[idx] {
+            Operand::Literal(_) => operand(idx),
+            _ => format!("*{}", operand(idx)),
+        }
+    };
+    let binary =
+        |symbol: &str| format!("{} = {} {} {};", operand(0), operand(1), symbol, operand(2));
+    match inst.op {
+        OpCodes::halt => "halt();".to_string(),
+        OpCodes::set => format!("{} = {};", operand(0), operand(1)),
+        OpCodes::push => format!("push({});", operand(0)),
+        OpCodes::pop => format!("{} = pop();", operand(0)),
+        OpCodes::eq => binary("=="),
+        OpCodes::gt => binary(">"),
+        OpCodes::jmp => format!("goto {};", target(0)),
+        OpCodes::jt => format!("if ({}) goto {};", operand(0), target(1)),
+        OpCodes::jf => format!("if (!{}) goto {};", operand(0), target(1)),
+        OpCodes::add => format!(
+            "{} = ({} + {}) % 32768;",
+            operand(0),
+            operand(1),
+            operand(2)
+        ),
+        OpCodes::mult => format!(
+            "{} = ({} * {}) % 32768;",
+            operand(0),
+            operand(1),
+            operand(2)
+        ),
+        OpCodes::mod_ => binary("%"),
+        OpCodes::and => binary("&"),
+        OpCodes::or => binary("|"),
+        OpCodes::not => format!("{} = ~{} & 0x7fff;", operand(0), operand(1)),
+        OpCodes::rmem => format!("{} = mem[{}];", operand(0), operand(1)),
+        OpCodes::wmem => format!("mem[{}] = {};", operand(0), operand(1)),
+        OpCodes::call => match inst.operands[0] {
+            Operand::Literal(_) => format!("{}();", operand(0)),
+            _ => format!("(*{})();", operand(0)),
+        },
+        OpCodes::ret => "return;".to_string(),
+        OpCodes::out => match inst.operands[0] {
+            Operand::Literal(value) => format!("putchar({});", char_literal(value)),
+            _ => format!("putchar({});", operand(0)),
+        },
+        OpCodes::in_ => format!("{} = getchar();", operand(0)),
+        OpCodes::nop => "/* nop */".to_string(),
+        OpCodes::unknown(value) => format!("/* unknown {} */", value),
+    }
+}
+
+pub fn pseudo_c(entries: &[Entry], format: &listing::Options) -> String {
+    let mut retr = String::from("void program() {\n");
+    let mut idx = 0;
+    while idx < entries.len() {
+        let entry = &entries[idx];
+        let address = format.address(entry.addr());
+        if let Some(label) = format.labels.get(&entry.addr()) {
+            retr += &format!("{}:\n", label);
+        }
+        let run = analysis::string_at(&entries[idx..], |addr| format.labels.contains_key(&addr));
+        let (text, count) = match (entry, run) {
+            (_, Some(run)) => (
+                format!("print(\"{}\");", run.text.escape_default()),
+                run.text.len(),
+            ),
+            (Entry::Code(inst), None) => (statement(inst, format), 1),
+            (Entry::Word(_, value), None) => (format!("/* .word {} */", value), 1),
+        };
+        retr += &format!("    /* {} */ {}\n", address, text);
+        idx += count;
+    }
+    retr += "}\n";
+    retr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use code::decompile::disassemble;
+
+    #[test]
+    fn statements() {
+        // 0: add r0 r1 4, 4: jt r0 0, 7: out H, 9: out i, 11: call r2, 13: ret
+        let data = [
+            9, 32768, 32769, 4, 7, 32768, 0, 19, 72, 19, 105, 17, 32770, 18,
+        ];
+        let entries = disassemble(&data, 0).entries;
+        let mut format = listing::Options {
+            registers: listing::RegisterNames::R,
+            ..listing::Options::default()
+        };
+        format.labels = listing::auto_labels(&entries);
+        assert_eq!(
+            pseudo_c(&entries, &format),
+            "void program() {\n\
+             L_0:\n    \
+             /* 0 */ r0 = (r1 + 4) % 32768;\n    \
+             /* 4 */ if (r0) goto L_0;\n    \
+             /* 7 */ print(\"Hi\");\n    \
+             /* 11 */ (*r2)();\n    \
+             /* 13 */ return;\n\
+             }\n"
+        );
+    }
+}
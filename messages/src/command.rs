@@ -5,15 +5,30 @@ peg::parser! {
     grammar command_grammar() for str {
         rule number() -> usize
             = digits:$(['0'..='9']+) {
-            usize::from_str_radix(digits, 10).unwrap()
+            digits.parse::<usize>().unwrap()
         }
 
         rule run() -> Command
             = "run" {? Ok(Command::Run) }
             / "r" {? Ok(Command::Run) }
+        rule step_count() -> usize
+            = count:number() {?
+                if count > 0 { Ok(count) } else { Err("step count must be at least 1") }
+            }
         rule step() -> Command
-            = "step" {? Ok(Command::Step) }
+            = "step " count:step_count() {? Ok(Command::StepCount(count)) }
+            / "s " count:step_count() {? Ok(Command::StepCount(count)) }
+            / "step" {? Ok(Command::Step) }
             / "s" {? Ok(Command::Step) }
+        rule next() -> Command
+            = "next" {? Ok(Command::Next) }
+            / "n" {? Ok(Command::Next) }
+        rule finish() -> Command
+            = "finish" {? Ok(Command::Finish) }
+            / "fin" {? Ok(Command::Finish) }
+        rule until() -> Command
+            = "until " addr:number() {? Ok(Command::Until(addr)) }
+            / "u " addr:number() {? Ok(Command::Until(addr)) }
         rule continue() -> Command
             = "continue" {? Ok(Command::Continue) }
             / "c" {? Ok(Command::Continue) }
@@ -58,18 +73,44 @@ peg::parser! {
             / "pm " addr:number() {?
                 Ok(Command::PrintMemory(addr, 1))
             }
+        rule set_mem() -> Command
+            = "set mem " addr:number() " " values:(number() ++ " ") {?
+                if values.iter().all(|v| *v <= 32775) {
+                    Ok(Command::SetMemory(addr, values.iter().map(|v| *v as u16).collect()))
+                } else {
+                    Err("Not a valid word")
+                }
+            }
+            / "sm " addr:number() " " values:(number() ++ " ") {?
+                if values.iter().all(|v| *v <= 32775) {
+                    Ok(Command::SetMemory(addr, values.iter().map(|v| *v as u16).collect()))
+                } else {
+                    Err("Not a valid word")
+                }
+            }
+        rule assemble() -> Command
+            = "asm " addr:number() {? Ok(Command::Assemble(addr)) }
+        rule xrefs() -> Command
+            = "xrefs " addr:number() {? Ok(Command::Xrefs(addr)) }
+
         rule print() -> Command
             = print_reg()
             / print_mem()
             / expected!("Failed to print command")
 
         pub rule parse_command() -> Command
-            = run()
+            = set_mem()
+            / run()
             / step()
+            / next()
+            / finish()
+            / until()
             / continue()
             / quit()
             / add_bp()
             / del_bp()
+            / assemble()
+            / xrefs()
             / print()
             / expected!("Failed to parse command")
     }
@@ -81,12 +122,22 @@ pub enum Command {
     None,
     Run,
     Step,
+    StepCount(usize),
+    Next,
+    Finish,
+    Until(usize),
     Continue,
     Quit,
     AddBreakpoint(usize),
     RemoveBreakpoint(usize),
     PrintRegister(usize),
     PrintMemory(usize, usize),
+    SetMemory(usize, Vec<u16>),
+    /// interactive assembly, handled by the debugger with SetMemory
+    Assemble(usize),
+    /// code and data references to an address, handled by the debugger
+    /// with PrintMemory
+    Xrefs(usize),
 }
 
 impl Command {
@@ -103,14 +154,14 @@ impl Command {
         Vec::from(json.as_bytes())
     }
     pub fn deserialize(data: &[u8]) -> Command {
-        let text = String::from_utf8_lossy(&data);
+        let text = String::from_utf8_lossy(data);
         serde_json::from_str(&text).unwrap()
     }
 }
 
 impl fmt::Display for Command {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self)
+        write!(f, "{:?}", self)
     }
 }
 
@@ -132,6 +183,16 @@ mod tests {
         assert_eq!(Command::parse("run").unwrap(), Command::Run);
         assert_eq!(Command::parse("s").unwrap(), Command::Step);
         assert_eq!(Command::parse("step").unwrap(), Command::Step);
+        assert_eq!(Command::parse("s 10").unwrap(), Command::StepCount(10));
+        assert_eq!(Command::parse("step 3").unwrap(), Command::StepCount(3));
+        assert!(Command::parse("s 0").is_err());
+        assert!(Command::parse("step 0").is_err());
+        assert_eq!(Command::parse("n").unwrap(), Command::Next);
+        assert_eq!(Command::parse("next").unwrap(), Command::Next);
+        assert_eq!(Command::parse("fin").unwrap(), Command::Finish);
+        assert_eq!(Command::parse("finish").unwrap(), Command::Finish);
+        assert_eq!(Command::parse("u 2125").unwrap(), Command::Until(2125));
+        assert_eq!(Command::parse("until 2125").unwrap(), Command::Until(2125));
         assert_eq!(Command::parse("c").unwrap(), Command::Continue);
         assert_eq!(Command::parse("continue").unwrap(), Command::Continue);
         assert_eq!(Command::parse("q").unwrap(), Command::Quit);
@@ -147,5 +208,11 @@ mod tests {
         assert_eq!(Command::parse("p mem 5 3").unwrap(), Command::PrintMemory(5, 3));
         assert_eq!(Command::parse("pm 5").unwrap(), Command::PrintMemory(5, 1));
         assert_eq!(Command::parse("pm 5 3").unwrap(), Command::PrintMemory(5, 3));
+
+        assert_eq!(Command::parse("set mem 5 21").unwrap(), Command::SetMemory(5, vec![21]));
+        assert_eq!(Command::parse("sm 5 6 32768").unwrap(), Command::SetMemory(5, vec![6, 32768]));
+        assert!(Command::parse("sm 5 40000").is_err());
+        assert_eq!(Command::parse("asm 5489").unwrap(), Command::Assemble(5489));
+        assert_eq!(Command::parse("xrefs 2125").unwrap(), Command::Xrefs(2125));
     }
 }
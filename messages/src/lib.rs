@@ -1,7 +1,33 @@
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path;
 
 pub mod command;
 
+pub const DEFAULT_PORT: u16 = 6565;
+
+/// Where the debugserver listens and the debugger connects
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoint {
+    Tcp(String),
+    Unix(path::PathBuf),
+}
+
+impl Endpoint {
+    pub fn tcp(host: &str, port: u16) -> Endpoint {
+        Endpoint::Tcp(format!("{}:{}", host, port))
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(address) => write!(f, "{}", address),
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Message {
     Request(command::Command),
@@ -16,7 +42,7 @@ impl Message {
     }
 
     pub fn deserialize(data: &[u8]) -> Message {
-        let text = String::from_utf8_lossy(&data);
+        let text = String::from_utf8_lossy(data);
         serde_json::from_str(&text).unwrap()
     }
 }
@@ -36,7 +62,7 @@ impl ResponseData {
     }
 
     pub fn deserialize(data: &[u8]) -> Message {
-        let text = String::from_utf8_lossy(&data);
+        let text = String::from_utf8_lossy(data);
         serde_json::from_str(&text).unwrap()
     }
 }
@@ -45,12 +71,12 @@ impl ResponseData {
 pub struct VmState {
     pub registers: [u16; 8],
     pub ip: usize,
-    pub count: u32,
+    pub count: u64,
     pub here: [u16; 20],
 }
 
 impl VmState {
-    pub fn from(registers: [u16; 8], ip: usize, count: u32, here: &[u16]) -> VmState {
+    pub fn from(registers: [u16; 8], ip: usize, count: u64, here: &[u16]) -> VmState {
         let mut buff = [0u16; 20];
         for (idx, h) in here.iter().enumerate() {
             buff[idx] = *h;
@@ -69,7 +95,7 @@ impl VmState {
     }
 
     pub fn deserialize(data: &[u8]) -> Message {
-        let text = String::from_utf8_lossy(&data);
+        let text = String::from_utf8_lossy(data);
         serde_json::from_str(&text).unwrap()
     }
 }
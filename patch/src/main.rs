@@ -0,0 +1,370 @@
+use std::env;
+use std::fs;
+use std::path;
+use std::process;
+
+use code::assemble;
+use code::decompile;
+use code::instruction::{self, DecodeError};
+use code::program::Program;
+
+// Applies a patch file to a binary and writes the result to a new file.
+//
+// Patch files have one patch per line, an address followed by either
+// assembly or raw words:
+//   # skip the teleporter check
+//   5489: nop; nop
+//   5491: jmp 5498
+//   6000: 21 21 21
+// Unless --force is given, a patch has to start and end on instruction
+// boundaries and every word it covers has to belong to an instruction.
+// Boundaries come from a linear sweep from address 0, so a patch can't start
+// on an operand; data that throws the sweep off needs --force.
+//
+// exit status: 0 patched, 1 patch rejected, 2 error
+
+struct Config {
+    input: String,
+    patch: String,
+    output: path::PathBuf,
+    force: bool,
+}
+
+fn default_output(input: &str) -> path::PathBuf {
+    let mut retr = path::PathBuf::from(input);
+    retr.set_extension("patched.bin");
+    retr
+}
+
+impl Config {
+    fn new(args: &[String]) -> Result<Config, String> {
+        let mut files = Vec::new();
+        let mut output = None;
+        let mut force = false;
+        let mut rest = args[1..].iter();
+        while let Some(arg) = rest.next() {
+            match arg.as_ref() {
+                "--output" | "-o" => match rest.next() {
+                    Some(path) => output = Some(path::PathBuf::from(path)),
+                    None => return Err(format!("{} needs a path", arg)),
+                },
+                "--force" => force = true,
+                _ => files.push(arg.clone()),
+            }
+        }
+        if files.len() != 2 {
+            return Err("usage: patch [--output OUT] [--force] BINARY PATCHFILE".to_string());
+        }
+        Ok(Config {
+            output: output.unwrap_or_else(|| default_output(&files[0])),
+            input: files[0].clone(),
+            patch: files[1].clone(),
+            force,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Patch {
+    line: usize,
+    address: usize,
+    words: Vec<u16>,
+}
+
+impl Patch {
+    fn end(&self) -> usize {
+        self.address + self.words.len()
+    }
+}
+
+fn parse_words(text: &str) -> Result<Vec<u16>, String> {
+    text.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|w| !w.is_empty())
+        .map(|w| {
+            w.parse::<u16>()
+                .map_err(|e| format!("invalid word '{}': {}", w, e))
+        })
+        .collect()
+}
+
+fn parse_patches(text: &str) -> Result<Vec<Patch>, String> {
+    let mut patches = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let (address, code) = match trimmed.split_once(':') {
+            Some(parts) => parts,
+            None => return Err(format!("line {}: expected ADDRESS: code", idx + 1)),
+        };
+        let address = address
+            .trim()
+            .parse::<usize>()
+            .map_err(|e| format!("line {}: invalid address '{}': {}", idx + 1, address, e))?;
+        // mnemonics never start with a digit
+        let raw = code.trim().starts_with(|c: char| c.is_ascii_digit());
+        let words = if raw {
+            parse_words(code.split('#').next().unwrap_or(""))
+        } else {
+            assemble::assemble(code)
+        }
+        .map_err(|e| format!("line {}: {}", idx + 1, e))?;
+        if words.is_empty() {
+            return Err(format!("line {}: empty patch", idx + 1));
+        }
+        patches.push(Patch {
+            line: idx + 1,
+            address,
+            words,
+        });
+    }
+    Ok(patches)
+}
+
+/// The start of the instruction each word belongs to, sweeping from address
+/// 0. None for words that don't decode.
+fn instruction_starts(data: &[u16]) -> Vec<Option<usize>> {
+    let mut starts = vec![None; data.len()];
+    let mut addr = 0;
+    while addr < data.len() {
+        let next = match instruction::decode(data, addr) {
+            Ok(inst) => inst.next(),
+            // the last instruction of the image may be cut off
+            Err(DecodeError::Truncated { .. }) => data.len(),
+            Err(_) => {
+                addr += 1;
+                continue;
+            }
+        };
+        for start in &mut starts[addr..next] {
+            *start = Some(addr);
+        }
+        addr = next;
+    }
+    starts
+}
+
+/// Whether `patch` starts and ends on instruction boundaries and only
+/// covers instructions
+fn check_boundaries(starts: &[Option<usize>], patch: &Patch) -> Result<(), String> {
+    let range = patch.address..patch.end();
+    if let Some(address) = range.clone().find(|a| starts[*a].is_none()) {
+        return Err(format!("{} is not an instruction", address));
+    }
+    if let Some(start) = starts[patch.address].filter(|s| *s != patch.address) {
+        return Err(format!(
+            "{} is inside the instruction at {}, patch from there",
+            patch.address, start
+        ));
+    }
+    match starts.get(patch.end()) {
+        Some(Some(start)) if *start != patch.end() => Err(format!(
+            "patch ends at {}, in the middle of an instruction, pad it with nop",
+            patch.end()
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn validate(data: &[u16], patches: &[Patch], force: bool) -> Vec<String> {
+    let mut errors = Vec::new();
+    let starts = instruction_starts(data);
+    for (idx, patch) in patches.iter().enumerate() {
+        if patch.end() > data.len() {
+            errors.push(format!(
+                "line {}: patch at {} ends at {}, past the end of the image ({})",
+                patch.line,
+                patch.address,
+                patch.end(),
+                data.len()
+            ));
+            continue;
+        }
+        for other in &patches[..idx] {
+            if patch.address < other.end() && other.address < patch.end() {
+                errors.push(format!(
+                    "line {}: patch overlaps the one on line {}",
+                    patch.line, other.line
+                ));
+            }
+        }
+        if force {
+            continue;
+        }
+        if let Err(what) = check_boundaries(&starts, patch) {
+            errors.push(format!("line {}: {}", patch.line, what));
+        }
+    }
+    errors
+}
+
+fn listing(words: &[u16], address: usize) -> Vec<String> {
+    decompile::disassemble(words, address)
+        .entries
+        .iter()
+        .map(|entry| entry.to_string())
+        .collect()
+}
+
+/// Applies the patches and describes what changed
+fn apply(data: &mut [u16], patches: &[Patch]) -> String {
+    let mut summary = String::new();
+    let mut total = 0;
+    for patch in patches {
+        let range = patch.address..patch.end();
+        let before = data[range.clone()].to_vec();
+        let changed = before
+            .iter()
+            .zip(patch.words.iter())
+            .filter(|(a, b)| a != b)
+            .count();
+        total += changed;
+        data[range.clone()].copy_from_slice(&patch.words);
+
+        summary += &format!(
+            "{}..{}: {} words, {} changed\n",
+            range.start,
+            range.end,
+            patch.words.len(),
+            changed
+        );
+        for line in listing(&before, patch.address) {
+            summary += &format!("- {}\n", line);
+        }
+        for line in listing(&patch.words, patch.address) {
+            summary += &format!("+ {}\n", line);
+        }
+    }
+    summary += &format!("{} patches, {} words changed\n", patches.len(), total);
+    summary
+}
+
+fn run(config: &Config) -> Result<bool, String> {
+    let mut program = Program::parse_file(path::Path::new(&config.input))
+        .map_err(|e| format!("{}: {}", config.input, e))?;
+    for warning in &program.warnings {
+        eprintln!("{}: {}", config.input, warning);
+    }
+    let text = fs::read_to_string(&config.patch)
+        .map_err(|e| format!("Failed to read {} : {}", config.patch, e))?;
+    let patches = parse_patches(&text)?;
+
+    let errors = validate(&program.data, &patches, config.force);
+    if !errors.is_empty() {
+        for error in errors {
+            eprintln!("{}", error);
+        }
+        return Ok(false);
+    }
+
+    print!("{}", apply(&mut program.data, &patches));
+    fs::write(&config.output, program.to_bytes())
+        .map_err(|e| format!("Failed to write {} : {}", config.output.display(), e))?;
+    println!("wrote {}", config.output.display());
+    Ok(true)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let result = Config::new(&args).and_then(|config| run(&config));
+    match result {
+        Ok(true) => process::exit(0),
+        Ok(false) => process::exit(1),
+        Err(what) => {
+            eprintln!("{}", what);
+            process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 0: set reg0 1, 3: call 10, 5: out A, 7: halt, 8: 1 2
+    const DATA: [u16; 10] = [1, 32768, 1, 17, 10, 19, 65, 0, 1, 2];
+
+    #[test]
+    fn parse_assembly_and_words() {
+        let patches = parse_patches("# comment\n3: nop; nop\n\n8: 7, 8 # data\n").unwrap();
+        assert_eq!(
+            patches,
+            vec![
+                Patch {
+                    line: 2,
+                    address: 3,
+                    words: vec![21, 21]
+                },
+                Patch {
+                    line: 4,
+                    address: 8,
+                    words: vec![7, 8]
+                },
+            ]
+        );
+        assert!(parse_patches("3 nop").is_err());
+    }
+
+    #[test]
+    fn boundaries() {
+        let ok = parse_patches("3: nop; nop").unwrap();
+        assert!(validate(&DATA, &ok, false).is_empty());
+
+        let middle = parse_patches("4: nop").unwrap();
+        assert_eq!(validate(&DATA, &middle, false).len(), 1);
+        assert!(validate(&DATA, &middle, true).is_empty());
+
+        let short = parse_patches("0: nop").unwrap();
+        assert_eq!(
+            validate(&DATA, &short, false),
+            vec!["line 1: patch ends at 1, in the middle of an instruction, pad it with nop"]
+        );
+
+        let operand = parse_patches("6: nop").unwrap();
+        assert_eq!(
+            validate(&DATA, &operand, false),
+            vec!["line 1: 6 is inside the instruction at 5, patch from there"]
+        );
+
+        // starts on the 1 of set reg0 1
+        let inside = parse_patches("2: nop; nop; nop").unwrap();
+        assert_eq!(
+            validate(&DATA, &inside, false),
+            vec!["line 1: 2 is inside the instruction at 0, patch from there"]
+        );
+        assert!(validate(&DATA, &inside, true).is_empty());
+
+        let unknown = parse_patches("0: nop; nop").unwrap();
+        assert_eq!(
+            validate(&[21, 30, 0], &unknown, false),
+            vec!["line 1: 1 is not an instruction"]
+        );
+
+        // the trailing data is cut off, it still counts as covered
+        let data = parse_patches("8: 7, 8").unwrap();
+        assert!(validate(&DATA, &data, false).is_empty());
+
+        let past = parse_patches("9: nop; nop").unwrap();
+        assert_eq!(validate(&DATA, &past, true).len(), 1);
+
+        // 0: set reg0 ... swallows the nops at 1 and 2, only --force gets
+        // past a sweep that data threw off
+        let drifted = [1, 21, 21, 0];
+        let nop = parse_patches("1: nop").unwrap();
+        assert_eq!(validate(&drifted, &nop, false).len(), 1);
+        assert!(validate(&drifted, &nop, true).is_empty());
+    }
+
+    #[test]
+    fn summary() {
+        let mut data = DATA.to_vec();
+        let patches = parse_patches("3: nop; nop").unwrap();
+        let summary = apply(&mut data, &patches);
+        assert_eq!(&data[3..5], &[21, 21]);
+        assert_eq!(
+            summary,
+            "3..5: 2 words, 2 changed\n- 3: call 10\n+ 3: nop\n+ 4: nop\n1 patches, 2 words changed\n"
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path;
+
+use log::{error, info};
+
+use code::instruction::decode;
+use code::opcodes::OpCodes;
+
+// Coverage files are plain text so they can be kept around and merged:
+//   hit ADDRESS COUNT
+//   branch ADDRESS TAKEN NOT_TAKEN
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Branch {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+/// Executed addresses and conditional branch outcomes
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Coverage {
+    hits: BTreeMap<usize, u64>,
+    branches: BTreeMap<usize, Branch>,
+    path: Option<path::PathBuf>,
+}
+
+impl Coverage {
+    /// Coverage that is saved to `path`, adding to whatever is already there
+    pub fn open(path: &path::Path) -> Result<Coverage, String> {
+        let mut coverage = if path.exists() {
+            Coverage::load(path)?
+        } else {
+            Coverage::default()
+        };
+        coverage.path = Some(path::PathBuf::from(path));
+        Ok(coverage)
+    }
+
+    pub fn load(path: &path::Path) -> Result<Coverage, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {} : {}", path.display(), e))?;
+        Coverage::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str) -> Result<Coverage, String> {
+        let mut coverage = Coverage::default();
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let numbers: Vec<u64> = parts[1..]
+                .iter()
+                .map(|p| p.parse::<u64>())
+                .collect::<Result<Vec<u64>, _>>()
+                .map_err(|e| format!("line {}: {}", lineno + 1, e))?;
+            match (parts[0], numbers.as_slice()) {
+                ("hit", [address, count]) => {
+                    *coverage.hits.entry(*address as usize).or_insert(0) += count
+                }
+                ("branch", [address, taken, not_taken]) => {
+                    let branch = coverage.branches.entry(*address as usize).or_default();
+                    branch.taken += taken;
+                    branch.not_taken += not_taken;
+                }
+                _ => return Err(format!("line {}: malformed entry '{}'", lineno + 1, line)),
+            }
+        }
+        Ok(coverage)
+    }
+
+    pub fn serialize(&self) -> String {
+        let mut retr = String::from("# synacor coverage\n");
+        for (address, count) in &self.hits {
+            retr += &format!("hit {} {}\n", address, count);
+        }
+        for (address, branch) in &self.branches {
+            retr += &format!("branch {} {} {}\n", address, branch.taken, branch.not_taken);
+        }
+        retr
+    }
+
+    pub fn merge(&mut self, other: &Coverage) {
+        for (address, count) in &other.hits {
+            *self.hits.entry(*address).or_insert(0) += count;
+        }
+        for (address, branch) in &other.branches {
+            let ours = self.branches.entry(*address).or_default();
+            ours.taken += branch.taken;
+            ours.not_taken += branch.not_taken;
+        }
+    }
+
+    /// Records an executed instruction, `taken` tells whether a jt or jf
+    /// jumped and is None for other instructions
+    pub fn record(&mut self, ip: usize, taken: Option<bool>) {
+        *self.hits.entry(ip).or_insert(0) += 1;
+        if let Some(taken) = taken {
+            let branch = self.branches.entry(ip).or_default();
+            if taken {
+                branch.taken += 1;
+            } else {
+                branch.not_taken += 1;
+            }
+        }
+    }
+
+    pub fn save(&self) {
+        if let Some(path) = &self.path {
+            match fs::write(path, self.serialize()) {
+                Ok(_) => info!("wrote coverage to {}", path.display()),
+                Err(what) => error!("Failed to write coverage {} : {}", path.display(), what),
+            }
+        }
+    }
+
+    fn hits(&self, address: usize) -> u64 {
+        self.hits.get(&address).copied().unwrap_or(0)
+    }
+
+    /// Disassembly of `data` with hit counts per instruction
+    pub fn annotate(&self, data: &[u16]) -> String {
+        let mut lines = Vec::new();
+        let mut instructions = 0;
+        let mut executed = 0;
+        let mut idx = 0;
+        while idx < data.len() {
+            let op = OpCodes::parse(data[idx]);
+            let len = 1 + op.argcount();
+            // the linear sweep can get out of step with the real code,
+            // anything that was executed starts an instruction
+            let len = (1..len).find(|o| self.hits(idx + o) > 0).unwrap_or(len);
+            let end = usize::min(idx + len, data.len());
+            let text = match decode(&data[..end], idx) {
+                Ok(inst) if inst.size() == len => inst.to_string(),
+                _ => format!("{}: .word {}", idx, data[idx]),
+            };
+
+            let hits = self.hits(idx);
+            let count = if hits > 0 {
+                executed += 1;
+                hits.to_string()
+            } else {
+                "-".to_string()
+            };
+            if op.info().is_some() {
+                instructions += 1;
+            }
+            let branch = match self.branches.get(&idx) {
+                Some(b) => format!("  [taken {}, not taken {}]", b.taken, b.not_taken),
+                None => String::new(),
+            };
+            lines.push(format!("{:>10} {}{}", count, text, branch));
+            idx += len;
+        }
+
+        let branches = self.branches.values();
+        let both = branches.filter(|b| b.taken > 0 && b.not_taken > 0).count();
+        let mut retr = format!(
+            "# {} of {} instructions executed ({:.2}%)\n",
+            executed,
+            instructions,
+            if instructions > 0 {
+                executed as f64 * 100.0 / instructions as f64
+            } else {
+                0.0
+            }
+        );
+        retr += &format!(
+            "# {} of {} executed branches went both ways\n",
+            both,
+            self.branches.len()
+        );
+        retr += &lines.join("\n");
+        retr += "\n";
+        retr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_merge_and_parse() {
+        let mut coverage = Coverage::default();
+        // jt r0 5 not taken, then taken
+        coverage.record(0, Some(false));
+        coverage.record(0, Some(true));
+        coverage.record(5, None);
+        let text = coverage.serialize();
+        assert_eq!(text, "# synacor coverage\nhit 0 2\nhit 5 1\nbranch 0 1 1\n");
+
+        let mut merged = Coverage::parse(&text).unwrap();
+        merged.merge(&coverage);
+        assert_eq!(merged.hits(0), 4);
+        assert_eq!(merged.branches[&0].taken, 2);
+    }
+
+    #[test]
+    fn annotated_listing() {
+        let mut coverage = Coverage::default();
+        coverage.record(0, Some(true));
+        coverage.record(5, None);
+        // jt reg0 5, out 'A', halt
+        let listing = coverage.annotate(&[7, 32768, 5, 19, 65, 0]);
+        assert_eq!(
+            listing,
+            "# 2 of 3 instructions executed (66.67%)\n\
+             # 0 of 1 executed branches went both ways\n         \
+             1 0: jt reg0 5  [taken 1, not taken 0]\n         \
+             - 3: out A\n         \
+             1 5: halt\n"
+        );
+    }
+}
@@ -0,0 +1,592 @@
+use std::convert::TryFrom;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+
+use log::{debug, info};
+use serde_json::{json, Value};
+
+use crate::host;
+use code::instruction::sweep;
+use code::opcodes::OpCodes;
+
+// Debug Adapter Protocol server.
+//
+// The binary has no source, the program file is presented as the source and
+// line numbers map directly to memory addresses.
+
+const THREAD_ID: i64 = 1;
+const REGISTERS_REF: i64 = 1;
+const STACK_REF: i64 = 2;
+const MEMORY_REF: i64 = 3;
+const MEMORY_WINDOW: usize = 32;
+const MEMORY_WORDS: usize = 32768;
+// most instructions a single disassemble request gets
+const MAX_DISASSEMBLE: usize = 4096;
+
+fn read_message<R: BufRead>(reader: &mut R) -> std::io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = match length {
+        Some(length) => length,
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "missing Content-Length header",
+            ))
+        }
+    };
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body)?;
+    let message = serde_json::from_slice(&body)?;
+    debug!("dap -> {}", message);
+    Ok(Some(message))
+}
+
+fn write_message<W: Write>(message: &Value, writer: &mut W) -> std::io::Result<()> {
+    debug!("dap <- {}", message);
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+fn parse_address(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse::<usize>().ok(),
+    }
+}
+
+pub struct DapServer {
+    host: host::Host,
+    /// setBreakpoints and setInstructionBreakpoints each replace their own
+    line_breakpoints: Vec<usize>,
+    instruction_breakpoints: Vec<usize>,
+    seq: i64,
+    stop_on_entry: bool,
+    /// the line of address 0, 1 unless the client sends linesStartAt1 false
+    first_line: usize,
+}
+
+impl DapServer {
+    pub fn new(host: host::Host) -> DapServer {
+        DapServer {
+            host,
+            line_breakpoints: Vec::new(),
+            instruction_breakpoints: Vec::new(),
+            seq: 0,
+            stop_on_entry: true,
+            first_line: 1,
+        }
+    }
+
+    pub fn start(host: host::Host, address: &str) {
+        let mut server = DapServer::new(host);
+        match server.listen(address) {
+            Ok(_) => {}
+            Err(what) => panic!("Error in dap communication {:?}", what),
+        }
+    }
+
+    fn listen(&mut self, address: &str) -> std::io::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        println!("dap server listening on {}", address);
+        for stream in listener.incoming() {
+            self.handle(stream?)?;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, stream: TcpStream) -> std::io::Result<()> {
+        println!("New dap connection {}", stream.peer_addr()?);
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        let result = self.serve(&mut reader, &mut writer);
+        self.host.end_session();
+        result?;
+        println!("dap client disconnected");
+        Ok(())
+    }
+
+    /// Handles requests until the client disconnects or asks to
+    pub fn serve<R: BufRead, W: Write>(
+        &mut self,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> std::io::Result<()> {
+        while let Some(request) = read_message(reader)? {
+            let (messages, done) = self.handle_request(&request);
+            for message in messages {
+                write_message(&message, writer)?;
+            }
+            if done {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn next_seq(&mut self) -> i64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    fn response(&mut self, request: &Value, body: Value) -> Value {
+        json!({
+            "seq": self.next_seq(),
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        })
+    }
+
+    fn error_response(&mut self, request: &Value, message: &str) -> Value {
+        json!({
+            "seq": self.next_seq(),
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        })
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> Value {
+        json!({
+            "seq": self.next_seq(),
+            "type": "event",
+            "event": event,
+            "body": body,
+        })
+    }
+
+    /// Returns the messages to send back, and whether the session is over
+    pub fn handle_request(&mut self, request: &Value) -> (Vec<Value>, bool) {
+        let command = request["command"].as_str().unwrap_or("");
+        let args = &request["arguments"];
+        info!("dap request {}", command);
+        let messages = match command {
+            "initialize" => self.handle_initialize(request, args),
+            "launch" | "attach" => self.handle_launch(request, args),
+            "configurationDone" => self.handle_configuration_done(request),
+            "setBreakpoints" => self.handle_set_breakpoints(request, args),
+            "setInstructionBreakpoints" => self.handle_set_instruction_breakpoints(request, args),
+            "threads" => self.handle_threads(request),
+            "stackTrace" => self.handle_stack_trace(request),
+            "scopes" => self.handle_scopes(request),
+            "variables" => self.handle_variables(request, args),
+            "stepIn" | "stepInstruction" => self.handle_step_in(request),
+            "next" => self.handle_next(request),
+            "continue" => self.handle_continue(request),
+            "disassemble" => self.handle_disassemble(request, args),
+            "disconnect" | "terminate" => {
+                let response = self.response(request, Value::Null);
+                return (vec![response], true);
+            }
+            _ => vec![self.error_response(request, &format!("unsupported request {}", command))],
+        };
+        (messages, false)
+    }
+
+    fn handle_initialize(&mut self, request: &Value, args: &Value) -> Vec<Value> {
+        self.first_line = match args["linesStartAt1"].as_bool() {
+            Some(false) => 0,
+            _ => 1,
+        };
+        let capabilities = json!({
+            "supportsConfigurationDoneRequest": true,
+            "supportsDisassembleRequest": true,
+            "supportsInstructionBreakpoints": true,
+            "supportsSteppingGranularity": true,
+        });
+        vec![
+            self.response(request, capabilities),
+            self.event("initialized", Value::Null),
+        ]
+    }
+
+    fn handle_launch(&mut self, request: &Value, args: &Value) -> Vec<Value> {
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(true);
+        vec![self.response(request, Value::Null)]
+    }
+
+    fn handle_configuration_done(&mut self, request: &Value) -> Vec<Value> {
+        let mut messages = vec![self.response(request, Value::Null)];
+        if self.stop_on_entry {
+            messages.push(self.stopped_event("entry"));
+        } else {
+            self.run();
+            messages.append(&mut self.stop_events("breakpoint"));
+        }
+        messages
+    }
+
+    fn is_breakpoint(&self, address: usize) -> bool {
+        self.line_breakpoints.contains(&address) || self.instruction_breakpoints.contains(&address)
+    }
+
+    fn verified(&self, addresses: &[Option<usize>]) -> Value {
+        let verified: Vec<Value> = addresses
+            .iter()
+            .map(|address| match address {
+                Some(address) => json!({ "verified": true, "line": address + self.first_line }),
+                None => json!({ "verified": false, "message": "not an address in memory" }),
+            })
+            .collect();
+        json!({ "breakpoints": verified })
+    }
+
+    fn handle_set_breakpoints(&mut self, request: &Value, args: &Value) -> Vec<Value> {
+        let addresses = match args["breakpoints"].as_array() {
+            Some(bps) => bps
+                .iter()
+                .map(|bp| {
+                    let line = bp["line"].as_u64()? as usize;
+                    line.checked_sub(self.first_line)
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        self.line_breakpoints = addresses.iter().filter_map(|a| *a).collect();
+        let body = self.verified(&addresses);
+        vec![self.response(request, body)]
+    }
+
+    fn handle_set_instruction_breakpoints(&mut self, request: &Value, args: &Value) -> Vec<Value> {
+        let addresses = match args["breakpoints"].as_array() {
+            Some(bps) => bps
+                .iter()
+                .map(|bp| {
+                    let base = bp["instructionReference"]
+                        .as_str()
+                        .and_then(parse_address)?;
+                    let offset = bp["offset"].as_i64().unwrap_or(0);
+                    let address = i64::try_from(base).ok()?.checked_add(offset)?;
+                    usize::try_from(address).ok().filter(|a| *a < MEMORY_WORDS)
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        self.instruction_breakpoints = addresses.iter().filter_map(|a| *a).collect();
+        let body = self.verified(&addresses);
+        vec![self.response(request, body)]
+    }
+
+    fn handle_threads(&mut self, request: &Value) -> Vec<Value> {
+        let body = json!({ "threads": [{ "id": THREAD_ID, "name": "vm" }] });
+        vec![self.response(request, body)]
+    }
+
+    fn frame(&self, id: usize, address: usize) -> Value {
+        let path = self.host.program.path.display().to_string();
+        json!({
+            "id": id,
+            "name": format!("{} {}", address, OpCodes::parse(self.host.peek(address))),
+            "source": { "name": path, "path": path },
+            "line": address + self.first_line,
+            "column": 0,
+            "instructionPointerReference": address.to_string(),
+        })
+    }
+
+    fn handle_stack_trace(&mut self, request: &Value) -> Vec<Value> {
+        let mut frames = vec![self.frame(0, self.host.ip())];
+        // the stack mixes data and return addresses, treat anything that
+        // points just past a call instruction as a return address
+        for value in self.host.stack().iter().rev() {
+            let address = *value as usize;
+            if address >= 2 && OpCodes::parse(self.host.peek(address - 2)) == OpCodes::call {
+                frames.push(self.frame(frames.len(), address));
+            }
+        }
+        let body = json!({ "stackFrames": frames, "totalFrames": frames.len() });
+        vec![self.response(request, body)]
+    }
+
+    fn handle_scopes(&mut self, request: &Value) -> Vec<Value> {
+        let body = json!({ "scopes": [
+            { "name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false },
+            { "name": "Stack", "variablesReference": STACK_REF, "expensive": false },
+            { "name": "Memory", "variablesReference": MEMORY_REF, "expensive": true },
+        ]});
+        vec![self.response(request, body)]
+    }
+
+    fn handle_variables(&mut self, request: &Value, args: &Value) -> Vec<Value> {
+        let variable = |name: String, value: u16| json!({ "name": name, "value": value.to_string(), "variablesReference": 0 });
+        let variables: Vec<Value> = match args["variablesReference"].as_i64() {
+            Some(REGISTERS_REF) => {
+                let mut regs: Vec<Value> = (0..8)
+                    .map(|reg| variable(format!("r{}", reg), self.host.register(reg)))
+                    .collect();
+                regs.push(variable("ip".to_string(), self.host.ip() as u16));
+                regs
+            }
+            Some(STACK_REF) => self
+                .host
+                .stack()
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(idx, value)| variable(format!("[{}]", idx), *value))
+                .collect(),
+            Some(MEMORY_REF) => (self.host.ip()..self.host.ip() + MEMORY_WINDOW)
+                .map(|address| variable(format!("{}", address), self.host.peek(address)))
+                .collect(),
+            _ => return vec![self.error_response(request, "unknown variables reference")],
+        };
+        let body = json!({ "variables": variables });
+        vec![self.response(request, body)]
+    }
+
+    fn stopped_event(&mut self, reason: &str) -> Value {
+        let body = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        self.event("stopped", body)
+    }
+
+    fn stop_events(&mut self, reason: &str) -> Vec<Value> {
+        if self.host.is_halted() || !self.host.should_run() {
+            vec![
+                self.event("exited", json!({ "exitCode": 0 })),
+                self.event("terminated", Value::Null),
+            ]
+        } else {
+            vec![self.stopped_event(reason)]
+        }
+    }
+
+    fn handle_step_in(&mut self, request: &Value) -> Vec<Value> {
+        let mut messages = vec![self.response(request, Value::Null)];
+        if self.host.should_run() {
+            self.host.step();
+        }
+        messages.append(&mut self.stop_events("step"));
+        messages
+    }
+
+    fn handle_next(&mut self, request: &Value) -> Vec<Value> {
+        let mut messages = vec![self.response(request, Value::Null)];
+        if let Some(return_address) = self.host.call_return_address() {
+            let depth = self.host.stack().len();
+            self.host.step();
+            while self.host.should_run()
+                && !(self.host.ip() == return_address && self.host.stack().len() == depth)
+                && !self.is_breakpoint(self.host.ip())
+            {
+                self.host.step();
+            }
+        } else if self.host.should_run() {
+            self.host.step();
+        }
+        let reason = if self.is_breakpoint(self.host.ip()) {
+            "breakpoint"
+        } else {
+            "step"
+        };
+        messages.append(&mut self.stop_events(reason));
+        messages
+    }
+
+    fn run(&mut self) {
+        // step off the current breakpoint before checking for the next one
+        if self.host.should_run() {
+            self.host.step();
+        }
+        while self.host.should_run() && !self.is_breakpoint(self.host.ip()) {
+            self.host.step();
+        }
+    }
+
+    fn handle_continue(&mut self, request: &Value) -> Vec<Value> {
+        let mut messages = vec![self.response(request, json!({ "allThreadsContinued": true }))];
+        self.run();
+        messages.append(&mut self.stop_events("breakpoint"));
+        messages
+    }
+
+    fn handle_disassemble(&mut self, request: &Value, args: &Value) -> Vec<Value> {
+        let base = match args["memoryReference"].as_str().and_then(parse_address) {
+            Some(base) => usize::min(base, MEMORY_WORDS) as i64,
+            None => return vec![self.error_response(request, "invalid memory reference")],
+        };
+        // offsets are in words, a negative instruction offset starts
+        // decoding that many words before the reference
+        let offset = args["offset"].as_i64().unwrap_or(0);
+        let instruction_offset = args["instructionOffset"].as_i64().unwrap_or(0);
+        let count = args["instructionCount"].as_u64().unwrap_or(0);
+        let count = u64::min(count, MAX_DISASSEMBLE as u64) as usize;
+        let words = MEMORY_WORDS as i64;
+        let skip = instruction_offset.clamp(0, words) as usize;
+        let start = base
+            .saturating_add(offset)
+            .saturating_add(i64::min(0, instruction_offset))
+            .clamp(0, words) as usize;
+
+        // every instruction is at most 4 words long, the sweep covers the
+        // skipped instructions and the ones returned
+        let data: Vec<u16> = (start..start + (skip + count) * 4)
+            .map(|a| self.host.peek(a))
+            .collect();
+        let mut instructions: Vec<Value> = sweep(&data)
+            .filter_map(Result::ok)
+            .skip(skip)
+            .take(count)
+            .map(|inst| {
+                let address = start + inst.addr;
+                json!({ "address": address.to_string(), "instruction": inst.assembly() })
+            })
+            .collect();
+        while instructions.len() < count {
+            instructions
+                .push(json!({ "address": "-1", "instruction": "", "presentationHint": "invalid" }));
+        }
+        let body = json!({ "instructions": instructions });
+        vec![self.response(request, body)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use code::program;
+    use std::thread;
+
+    struct Client {
+        writer: TcpStream,
+        reader: BufReader<TcpStream>,
+        seq: i64,
+    }
+
+    impl Client {
+        fn request(&mut self, command: &str, arguments: Value) -> Value {
+            self.seq += 1;
+            let request = json!({
+                "seq": self.seq,
+                "type": "request",
+                "command": command,
+                "arguments": arguments,
+            });
+            write_message(&request, &mut self.writer).unwrap();
+            loop {
+                let message = self.next();
+                if message["type"] == "response" {
+                    assert_eq!(message["request_seq"], self.seq);
+                    return message;
+                }
+            }
+        }
+
+        fn next(&mut self) -> Value {
+            read_message(&mut self.reader).unwrap().unwrap()
+        }
+    }
+
+    #[test]
+    fn scripted_session() {
+        // 0: call 4, 2: halt, 3: nop, 4: set r0 7, 7: ret
+        let mut program = program::Program::new();
+        program.data = vec![17, 4, 0, 21, 1, 32768, 7, 18];
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut server = DapServer::new(host::Host::from(program));
+            let (stream, _) = listener.accept().unwrap();
+            server.handle(stream).unwrap();
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        let mut client = Client {
+            writer: stream.try_clone().unwrap(),
+            reader: BufReader::new(stream),
+            seq: 0,
+        };
+
+        let response = client.request("initialize", json!({ "adapterID": "synacor" }));
+        assert_eq!(response["body"]["supportsDisassembleRequest"], true);
+        assert_eq!(client.next()["event"], "initialized");
+
+        client.request("launch", json!({ "stopOnEntry": true }));
+        // lines start at 1, line 8 is address 7
+        let response = client.request("setBreakpoints", json!({ "breakpoints": [{ "line": 8 }] }));
+        assert_eq!(response["body"]["breakpoints"][0]["verified"], true);
+        assert_eq!(response["body"]["breakpoints"][0]["line"], 8);
+        // replaces only the instruction breakpoints, 7 stays
+        client.request(
+            "setInstructionBreakpoints",
+            json!({ "breakpoints": [{ "instructionReference": "3" }] }),
+        );
+        let response = client.request(
+            "setInstructionBreakpoints",
+            json!({ "breakpoints": [{ "instructionReference": "3", "offset": -5 }] }),
+        );
+        assert_eq!(response["body"]["breakpoints"][0]["verified"], false);
+        client.request("setInstructionBreakpoints", json!({ "breakpoints": [] }));
+        client.request("configurationDone", json!({}));
+        assert_eq!(client.next()["body"]["reason"], "entry");
+
+        let response = client.request(
+            "disassemble",
+            json!({ "memoryReference": "0", "instructionCount": 2 }),
+        );
+        assert_eq!(response["body"]["instructions"][0]["instruction"], "call 4");
+        assert_eq!(response["body"]["instructions"][1]["address"], "2");
+        let response = client.request(
+            "disassemble",
+            json!({ "memoryReference": "4", "instructionCount": 2 }),
+        );
+        assert_eq!(response["body"]["instructions"][0]["address"], "4");
+        assert_eq!(
+            response["body"]["instructions"][0]["instruction"],
+            "set reg0 7"
+        );
+        assert_eq!(response["body"]["instructions"][1]["instruction"], "ret");
+        // past the end of the program memory reads as halt
+        let response = client.request(
+            "disassemble",
+            json!({ "memoryReference": "0", "instructionOffset": 4, "instructionCount": 2 }),
+        );
+        assert_eq!(response["body"]["instructions"][0]["address"], "7");
+        assert_eq!(response["body"]["instructions"][1]["address"], "8");
+        let response = client.request(
+            "disassemble",
+            json!({ "memoryReference": "0", "instructionCount": u64::MAX }),
+        );
+        let instructions = response["body"]["instructions"].as_array().unwrap();
+        assert_eq!(instructions.len(), MAX_DISASSEMBLE);
+
+        client.request("stepIn", json!({ "threadId": 1 }));
+        assert_eq!(client.next()["body"]["reason"], "step");
+        let response = client.request("stackTrace", json!({ "threadId": 1 }));
+        assert_eq!(response["body"]["stackFrames"][0]["line"], 5);
+        assert_eq!(response["body"]["stackFrames"][1]["line"], 3);
+
+        client.request("continue", json!({ "threadId": 1 }));
+        assert_eq!(client.next()["body"]["reason"], "breakpoint");
+        let response = client.request("variables", json!({ "variablesReference": REGISTERS_REF }));
+        assert_eq!(response["body"]["variables"][0]["value"], "7");
+        assert_eq!(response["body"]["variables"][8]["value"], "7");
+
+        client.request("next", json!({ "threadId": 1 }));
+        assert_eq!(client.next()["body"]["reason"], "step");
+        client.request("continue", json!({ "threadId": 1 }));
+        assert_eq!(client.next()["event"], "exited");
+        assert_eq!(client.next()["event"], "terminated");
+
+        client.request("disconnect", json!({}));
+        server.join().unwrap();
+    }
+}
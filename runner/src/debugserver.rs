@@ -1,27 +1,39 @@
 use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::process;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError};
+use std::thread;
 
 use log::{debug, error, info};
+use signal_hook::consts::SIGINT;
+use signal_hook::iterator::Signals;
 
 use crate::host;
-use code::program;
+use code::opcodes::OpCodes;
 use messages::command::Command;
-use messages::{Message, ResponseData};
+use messages::{Endpoint, Message, ResponseData};
 
-fn send(data: &[u8], stream: &mut TcpStream) -> std::io::Result<()> {
+/// Any connection a debugger can talk to us over
+pub trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+fn send(data: &[u8], stream: &mut dyn Stream) -> std::io::Result<()> {
     let len: u64 = data.len() as u64;
-    stream.write(&len.to_le_bytes())?;
-    stream.write(&data)?;
+    stream.write_all(&len.to_le_bytes())?;
+    stream.write_all(data)?;
     stream.flush()
 }
 
-fn send_string(text: String, stream: &mut TcpStream) -> std::io::Result<()> {
+fn send_string(text: String, stream: &mut dyn Stream) -> std::io::Result<()> {
     //send(text.as_bytes(), stream)?;
     send_response(ResponseData::Text(text), stream)?;
     Ok(())
 }
 
-fn send_responses(data: Vec<ResponseData>, stream: &mut TcpStream) -> std::io::Result<()> {
+fn send_responses(data: Vec<ResponseData>, stream: &mut dyn Stream) -> std::io::Result<()> {
     debug!("sending response {:?}", data);
     let response = Message::Responses(data);
     let jsondata = response.serialize();
@@ -29,7 +41,7 @@ fn send_responses(data: Vec<ResponseData>, stream: &mut TcpStream) -> std::io::R
     Ok(())
 }
 
-fn send_response(data: ResponseData, stream: &mut TcpStream) -> std::io::Result<()> {
+fn send_response(data: ResponseData, stream: &mut dyn Stream) -> std::io::Result<()> {
     debug!("sending response {:?}", data);
     let response = Message::Response(data);
     let jsondata = response.serialize();
@@ -37,25 +49,131 @@ fn send_response(data: ResponseData, stream: &mut TcpStream) -> std::io::Result<
     Ok(())
 }
 
-fn recv_cmd(stream: &mut TcpStream) -> std::io::Result<Command> {
+/// Reads the next command, None when the peer has disconnected
+fn recv_cmd(stream: &mut dyn Stream) -> std::io::Result<Option<Command>> {
     // length
     let mut buffer = [0; 8];
-    let numbytes = stream.take(8).read(&mut buffer)?;
-    if numbytes == 0 {
-        return Ok(Command::None);
+    match stream.read_exact(&mut buffer) {
+        Ok(_) => {}
+        Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
     }
-    assert_eq!(numbytes, 8);
     let len = u64::from_le_bytes(buffer);
 
     // data
     let mut data = Vec::new();
     stream.take(len).read_to_end(&mut data)?;
-    if let Message::Request(cmd) = Message::deserialize(&data) {
-        debug!("received request {:?}", cmd);
-        Ok(cmd)
-    } else {
-        panic!("command not a request");
+    if data.len() as u64 != len {
+        return Ok(None);
+    }
+    match Message::deserialize(&data) {
+        Message::Request(cmd) => {
+            debug!("received request {:?}", cmd);
+            Ok(Some(cmd))
+        }
+        msg => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("command not a request: {:?}", msg),
+        )),
+    }
+}
+
+fn listen_tcp(server: Arc<Mutex<Debugserver>>, address: &str) -> std::io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    println!("server listening on {}", address);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let peer = stream.peer_addr()?.to_string();
+        spawn_session(server.clone(), stream, peer);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn listen_unix(server: Arc<Mutex<Debugserver>>, path: &std::path::Path) -> std::io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    // a socket file left behind by an earlier run would make bind fail,
+    // anything else at that path is left alone
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    println!("server listening on {}", path.display());
+    for (idx, stream) in listener.incoming().enumerate() {
+        spawn_session(server.clone(), stream?, format!("unix client {}", idx));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn listen_unix(_server: Arc<Mutex<Debugserver>>, _path: &std::path::Path) -> std::io::Result<()> {
+    Err(std::io::Error::other("unix sockets are not supported on this platform"))
+}
+
+fn spawn_session<S: Stream + Send + 'static>(server: Arc<Mutex<Debugserver>>, mut stream: S, peer: String) {
+    thread::spawn(move || {
+        println!("New connection {}", peer);
+        match session(&server, &mut stream) {
+            Ok(_) => println!("{} disconnected", peer),
+            Err(what) => error!("session with {} ended: {}", peer, what),
+        }
+    });
+}
+
+/// A session that panicked poisons the lock, the vm is still usable by the
+/// next client
+fn lock(server: &Mutex<Debugserver>) -> MutexGuard<'_, Debugserver> {
+    server.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Serves one client. The vm is shared between sessions, a client that
+/// disconnects leaves it paused for the next one to pick up.
+fn session(server: &Mutex<Debugserver>, stream: &mut dyn Stream) -> std::io::Result<()> {
+    let greeting = lock(server).greeting();
+    stream.write_all(greeting.as_bytes())?;
+    stream.flush()?;
+    let result = commands(server, stream);
+    lock(server).host.end_session();
+    result
+}
+
+fn commands(server: &Mutex<Debugserver>, stream: &mut dyn Stream) -> std::io::Result<()> {
+    while let Some(cmd) = recv_cmd(stream)? {
+        let mut ds = lock(server);
+        if ds.handle_cmd(cmd, stream)? {
+            break;
+        }
     }
+    Ok(())
+}
+
+/// Ctrl-C doesn't end the sessions, writes out what the vm collected
+/// before exiting. A vm that is running can't be saved.
+fn save_on_interrupt(server: Arc<Mutex<Debugserver>>) {
+    let mut signals = match Signals::new([SIGINT]) {
+        Ok(signals) => signals,
+        Err(what) => {
+            error!("Ctrl-C will not save profiles or coverage: {}", what);
+            return;
+        }
+    };
+    thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            match server.try_lock() {
+                Ok(mut ds) => ds.host.finish(),
+                Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner().host.finish(),
+                Err(TryLockError::WouldBlock) => error!("vm is running, nothing saved"),
+            }
+            process::exit(130);
+        }
+    });
 }
 
 pub struct Debugserver {
@@ -65,68 +183,65 @@ pub struct Debugserver {
 }
 
 impl Debugserver {
-    pub fn start(program: program::Program) {
-        let mut ds = Debugserver {
-            host: host::Host::from(program),
+    pub fn start(host: host::Host, endpoint: &Endpoint) {
+        let ds = Arc::new(Mutex::new(Debugserver {
+            host,
             breakpoints: Vec::new(),
             hit_breakpoint: 0,
+        }));
+        save_on_interrupt(ds.clone());
+        let result = match endpoint {
+            Endpoint::Tcp(address) => listen_tcp(ds, address),
+            Endpoint::Unix(path) => listen_unix(ds, path),
         };
-        match ds.listen() {
+        match result {
             Ok(_) => {}
             Err(what) => panic!("Error in network communication {:?}", what),
         }
     }
 
-    fn listen(&mut self) -> std::io::Result<()> {
-        let listener = TcpListener::bind("0.0.0.0:6565")?;
-        println!("server listening");
-        for stream in listener.incoming() {
-            self.handle(&mut stream?)?;
-        }
-        Ok(())
-    }
-
-    fn handle(&mut self, stream: &mut TcpStream) -> std::io::Result<()> {
-        println!("New connection {}", stream.peer_addr().unwrap());
+    fn greeting(&self) -> String {
         let mut greeting = String::from("Running ");
         greeting += self.host.program.path.to_str().unwrap();
-        stream.write(greeting.as_bytes())?;
-        stream.flush()?;
-        loop {
-            if let Ok(cmd) = recv_cmd(stream) {
-                if let Ok(do_quit) = self.handle_cmd(cmd, stream) {
-                    if do_quit {
-                        break;
-                    }
-                }
-            }
-        }
-        Ok(())
+        greeting += &format!(", paused at {}", self.host.ip());
+        greeting
     }
 
-    fn handle_cmd(&mut self, cmd: Command, stream: &mut TcpStream) -> std::io::Result<bool> {
+    fn handle_cmd(&mut self, cmd: Command, stream: &mut dyn Stream) -> std::io::Result<bool> {
         info!("got command {:?}", cmd);
         match cmd {
             Command::None => Ok(false),
             Command::Quit => self.handle_quit(stream),
             Command::Run => self.handle_run(stream),
             Command::Step => self.handle_step(stream),
+            Command::StepCount(count) => self.handle_step_count(count, stream),
+            Command::Next => self.handle_next(stream),
+            Command::Finish => self.handle_finish(stream),
+            Command::Until(address) => self.handle_until(address, stream),
             Command::Continue => self.handle_continue(stream),
             Command::AddBreakpoint(address) => self.handle_add_breakpoint(address, stream),
             Command::RemoveBreakpoint(address) => self.handle_remove_breakpoint(address, stream),
             Command::PrintRegister(_) => self.handle_print_register(stream),
             Command::PrintMemory(address, len) => self.handle_print_memory(address, len, stream),
-            _ => panic!("unknown command {:?}", cmd),
+            Command::SetMemory(address, values) => self.handle_set_memory(address, values, stream),
+            Command::Assemble(_) => {
+                send_string("asm is handled by the debugger".to_string(), stream)?;
+                Ok(false)
+            }
+            Command::Xrefs(_) => {
+                send_string("xrefs is handled by the debugger".to_string(), stream)?;
+                Ok(false)
+            }
         }
     }
 
-    fn handle_quit(&mut self, _stream: &mut TcpStream) -> std::io::Result<bool> {
+    fn handle_quit(&mut self, _stream: &mut dyn Stream) -> std::io::Result<bool> {
         println!("Client disconnected");
         // client shuts down the stream
         Ok(true)
     }
 
-    fn handle_run(&mut self, stream: &mut TcpStream) -> std::io::Result<bool> {
+    fn handle_run(&mut self, stream: &mut dyn Stream) -> std::io::Result<bool> {
         send_string("Running program...".to_string(), stream)?;
         self.run();
         let state = self.host.create_state();
@@ -134,16 +249,63 @@ impl Debugserver {
         Ok(false)
     }
 
-    fn handle_step(&mut self, stream: &mut TcpStream) -> std::io::Result<bool> {
+    fn handle_step(&mut self, stream: &mut dyn Stream) -> std::io::Result<bool> {
         self.host.step();
         let state = self.host.create_state();
         send_response(ResponseData::State(state), stream)?;
         Ok(false)
     }
 
-    fn handle_continue(&mut self, stream: &mut TcpStream) -> std::io::Result<bool> {
+    fn handle_step_count(&mut self, count: usize, stream: &mut dyn Stream) -> std::io::Result<bool> {
+        // the parser refuses 0, a client sending it anyway steps once
+        let mut remaining = count;
+        self.run_until(|_, _| {
+            remaining = remaining.saturating_sub(1);
+            remaining == 0
+        });
+        self.send_stop(stream)
+    }
+
+    fn handle_continue(&mut self, stream: &mut dyn Stream) -> std::io::Result<bool> {
         send_string("Continuing execution".to_string(), stream)?;
         self.run();
+        self.send_stop(stream)
+    }
+
+    fn handle_next(&mut self, stream: &mut dyn Stream) -> std::io::Result<bool> {
+        match self.host.call_return_address() {
+            Some(return_address) => {
+                send_string(format!("Stepping over call, until {}", return_address), stream)?;
+                // a recursive call can come back to the same address deeper
+                // down the stack, only stop once our frame is back
+                let depth = self.host.stack().len();
+                self.run_until(|host, _| host.ip() == return_address && host.stack().len() == depth);
+            }
+            None => {
+                send_string("Stepping".to_string(), stream)?;
+                self.run_until(|_, _| true);
+            }
+        }
+        self.send_stop(stream)
+    }
+
+    fn handle_finish(&mut self, stream: &mut dyn Stream) -> std::io::Result<bool> {
+        send_string("Running until the current function returns".to_string(), stream)?;
+        // the return address sits somewhere below the current stack top, the
+        // frame is done when a ret takes the stack below where we started
+        let depth = self.host.stack().len();
+        self.run_until(|host, op| *op == OpCodes::ret && host.stack().len() < depth);
+        self.send_stop(stream)
+    }
+
+    fn handle_until(&mut self, address: usize, stream: &mut dyn Stream) -> std::io::Result<bool> {
+        send_string(format!("Running until {}", address), stream)?;
+        self.run_until(|host, _| host.ip() == address);
+        self.send_stop(stream)
+    }
+
+    /// Sends where execution stopped and why
+    fn send_stop(&mut self, stream: &mut dyn Stream) -> std::io::Result<bool> {
         let state = self.host.create_state();
         let mut responses = Vec::new();
         if self.hit_breakpoint != 0 {
@@ -152,6 +314,9 @@ impl Debugserver {
                 self.hit_breakpoint
             )));
         }
+        if self.host.is_halted() {
+            responses.push(ResponseData::Text("Program halted".to_string()));
+        }
         responses.push(ResponseData::State(state));
         send_responses(responses, stream)?;
         Ok(false)
@@ -160,7 +325,7 @@ impl Debugserver {
     fn handle_add_breakpoint(
         &mut self,
         address: usize,
-        stream: &mut TcpStream,
+        stream: &mut dyn Stream,
     ) -> std::io::Result<bool> {
         self.breakpoints.push(address);
         let bp_id = self.breakpoints.len();
@@ -171,10 +336,10 @@ impl Debugserver {
     fn handle_remove_breakpoint(
         &mut self,
         address: usize,
-        stream: &mut TcpStream,
+        stream: &mut dyn Stream,
     ) -> std::io::Result<bool> {
         if self.breakpoints.contains(&address) {
-            self.breakpoints.remove(address);
+            self.breakpoints.retain(|bp| *bp != address);
             send_string(format!("Breakpint at {} removed", address), stream)?;
         } else {
             send_string(format!("No breakpint at {}", address), stream)?;
@@ -182,14 +347,36 @@ impl Debugserver {
         Ok(false)
     }
 
-    fn handle_print_register(&mut self, stream : &mut TcpStream) -> std::io::Result<bool> {
+    fn handle_print_register(&mut self, stream : &mut dyn Stream) -> std::io::Result<bool> {
         let state = self.host.create_state();
         send_response(ResponseData::State(state), stream)?;
         Ok(false)
     }
 
-    fn handle_print_memory(&mut self, address : usize, len : usize, stream : &mut TcpStream) -> std::io::Result<bool> {
-        let dump = self.host.create_memory_dump(address, address+len);
+    fn handle_print_memory(&mut self, address : usize, len : usize, stream : &mut dyn Stream) -> std::io::Result<bool> {
+        let dump = self.host.create_memory_dump(address, len);
+        send_response(ResponseData::Dump(address, dump), stream)?;
+        Ok(false)
+    }
+
+    fn handle_set_memory(
+        &mut self,
+        address: usize,
+        values: Vec<u16>,
+        stream: &mut dyn Stream,
+    ) -> std::io::Result<bool> {
+        match address.checked_add(values.len()) {
+            Some(end) if end <= 32768 => {}
+            _ => {
+                let what = format!("{} words at {} don't fit in memory", values.len(), address);
+                send_string(what, stream)?;
+                return Ok(false);
+            }
+        }
+        for (offset, value) in values.iter().enumerate() {
+            self.host.poke(address + offset, *value);
+        }
+        let dump = self.host.create_memory_dump(address, values.len());
         send_response(ResponseData::Dump(address, dump), stream)?;
         Ok(false)
     }
@@ -208,12 +395,131 @@ impl Debugserver {
         false
     }
 
-    fn run(&mut self) {
+    /// Runs until a breakpoint, the program stops or `done` returns true.
+    /// `done` gets the host and the instruction that was just executed. At
+    /// least one instruction is run so we can resume from a breakpoint.
+    fn run_until<F>(&mut self, mut done: F)
+    where
+        F: FnMut(&host::Host, &OpCodes) -> bool,
+    {
         self.hit_breakpoint = 0;
-        let mut last_ip = 0;
-        while !self.breakpoint_hit(last_ip) && self.host.should_run() {
-            last_ip = self.host.ip();
+        let mut last_ip = self.host.ip();
+        while self.host.should_run() {
+            let op = self.host.current_op();
             self.host.step();
+            if done(&self.host, &op) || self.breakpoint_hit(last_ip) {
+                break;
+            }
+            last_ip = self.host.ip();
+        }
+    }
+
+    fn run(&mut self) {
+        self.run_until(|_, _| false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use code::program;
+    use std::io::Cursor;
+
+    fn server(data: Vec<u16>) -> Debugserver {
+        let mut program = program::Program::new();
+        program.data = data;
+        Debugserver {
+            host: host::Host::from(program),
+            breakpoints: Vec::new(),
+            hit_breakpoint: 0,
         }
     }
+
+    fn exec(ds: &mut Debugserver, cmd: Command) {
+        let mut stream = Cursor::new(Vec::new());
+        ds.handle_cmd(cmd, &mut stream).unwrap();
+    }
+
+    // 0: call 5, 2: call 5, 4: halt, 5: push r0, 7: call 11, 9: pop r0, 11: ret
+    const PROGRAM: [u16; 12] = [17, 5, 17, 5, 0, 2, 32768, 17, 11, 3, 32768, 18];
+
+    #[test]
+    fn next_steps_over_calls() {
+        let mut ds = server(PROGRAM.to_vec());
+        exec(&mut ds, Command::Next);
+        assert_eq!(ds.host.ip(), 2);
+        ds.breakpoints.push(11);
+        exec(&mut ds, Command::Next);
+        assert_eq!(ds.host.ip(), 11);
+        assert_eq!(ds.hit_breakpoint, 11);
+    }
+
+    #[test]
+    fn finish_returns_to_caller() {
+        let mut ds = server(PROGRAM.to_vec());
+        exec(&mut ds, Command::StepCount(2));
+        assert_eq!(ds.host.ip(), 7);
+        exec(&mut ds, Command::Finish);
+        assert_eq!(ds.host.ip(), 2);
+        assert!(ds.host.stack().is_empty());
+    }
+
+    #[test]
+    fn step_count_zero() {
+        let mut ds = server(PROGRAM.to_vec());
+        exec(&mut ds, Command::StepCount(0));
+        assert_eq!(ds.host.ip(), 5);
+    }
+
+    #[test]
+    fn set_memory() {
+        let mut ds = server(PROGRAM.to_vec());
+        exec(&mut ds, Command::SetMemory(0, vec![21, 21]));
+        exec(&mut ds, Command::Step);
+        assert_eq!(ds.host.ip(), 1);
+        assert_eq!(ds.host.peek(1), 21);
+
+        for address in [32767, usize::MAX].iter() {
+            let mut stream = Cursor::new(Vec::new());
+            ds.handle_cmd(Command::SetMemory(*address, vec![1, 2]), &mut stream)
+                .unwrap();
+            let reply = String::from_utf8_lossy(stream.get_ref()).to_string();
+            assert!(reply.contains("don't fit in memory"));
+        }
+        assert_eq!(ds.host.memory_size(), PROGRAM.len());
+    }
+
+    #[test]
+    fn until_address() {
+        let mut ds = server(PROGRAM.to_vec());
+        exec(&mut ds, Command::Until(9));
+        assert_eq!(ds.host.ip(), 9);
+        exec(&mut ds, Command::Until(4));
+        assert_eq!(ds.host.ip(), 4);
+    }
+
+    #[test]
+    fn poisoned_lock_recovers() {
+        let ds = Arc::new(Mutex::new(server(PROGRAM.to_vec())));
+        let shared = ds.clone();
+        let _ = thread::spawn(move || {
+            let _guard = shared.lock().unwrap();
+            panic!("handler failed");
+        })
+        .join();
+        assert!(ds.is_poisoned());
+        exec(&mut lock(&ds), Command::Step);
+        assert_eq!(lock(&ds).host.ip(), 5);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket_keeps_regular_files() {
+        let path = std::env::temp_dir().join(format!("debugserver-{}.txt", std::process::id()));
+        std::fs::write(&path, "keep").unwrap();
+        let ds = Arc::new(Mutex::new(server(PROGRAM.to_vec())));
+        assert!(listen_unix(ds, &path).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep");
+        std::fs::remove_file(&path).unwrap();
+    }
 }
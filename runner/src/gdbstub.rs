@@ -0,0 +1,439 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use log::{debug, info};
+
+use crate::host;
+
+// GDB remote serial protocol stub.
+//
+// gdb is byte addressed while the vm is word addressed, memory is exposed as
+// little endian byte pairs so byte address = word address * 2. pc is reported
+// the same way so breakpoints and memory reads line up.
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.synacor.core">
+    <reg name="r0" bitsize="16" type="uint16" regnum="0"/>
+    <reg name="r1" bitsize="16" type="uint16"/>
+    <reg name="r2" bitsize="16" type="uint16"/>
+    <reg name="r3" bitsize="16" type="uint16"/>
+    <reg name="r4" bitsize="16" type="uint16"/>
+    <reg name="r5" bitsize="16" type="uint16"/>
+    <reg name="r6" bitsize="16" type="uint16"/>
+    <reg name="r7" bitsize="16" type="uint16"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+const PC_REGNUM: usize = 8;
+const MEMORY_WORDS: usize = 32768;
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+}
+
+fn encode_word(value: u16) -> String {
+    let bytes = value.to_le_bytes();
+    format!("{:02x}{:02x}", bytes[0], bytes[1])
+}
+
+fn decode_word(text: &str) -> Option<u16> {
+    if text.len() != 4 {
+        return None;
+    }
+    let lo = u8::from_str_radix(&text[0..2], 16).ok()?;
+    let hi = u8::from_str_radix(&text[2..4], 16).ok()?;
+    Some(u16::from_le_bytes([lo, hi]))
+}
+
+fn decode_bytes(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&text[idx..idx + 2], 16).ok())
+        .collect()
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+fn parse_addr_len(text: &str) -> Option<(usize, usize)> {
+    let mut parts = text.splitn(2, ',');
+    let addr = parse_hex(parts.next()?)?;
+    let len = parse_hex(parts.next()?)?;
+    Some((addr, len))
+}
+
+fn send_packet(data: &str, stream: &mut TcpStream) -> std::io::Result<()> {
+    debug!("gdb <- {}", data);
+    let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+    stream.write_all(packet.as_bytes())?;
+    stream.flush()
+}
+
+/// Reads the next packet, acking it. Returns None when the peer disconnects.
+fn recv_packet(stream: &mut TcpStream) -> std::io::Result<Option<String>> {
+    let mut byte = [0u8; 1];
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        // skip acks and interrupts until the start of the next packet
+        if byte[0] == b'$' {
+            break;
+        }
+    }
+    let mut data = Vec::new();
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        if byte[0] == b'#' {
+            break;
+        }
+        data.push(byte[0]);
+    }
+    let mut sum = [0u8; 2];
+    stream.read_exact(&mut sum)?;
+    let expected = u8::from_str_radix(&String::from_utf8_lossy(&sum), 16).unwrap_or(0);
+    if expected != checksum(&data) {
+        stream.write_all(b"-")?;
+        return recv_packet(stream);
+    }
+    stream.write_all(b"+")?;
+    let packet = String::from_utf8_lossy(&data).to_string();
+    debug!("gdb -> {}", packet);
+    Ok(Some(packet))
+}
+
+pub struct GdbStub {
+    host: host::Host,
+    breakpoints: Vec<usize>,
+}
+
+impl GdbStub {
+    pub fn start(host: host::Host, address: &str) {
+        let mut stub = GdbStub {
+            host,
+            breakpoints: Vec::new(),
+        };
+        match stub.listen(address) {
+            Ok(_) => {}
+            Err(what) => panic!("Error in gdb communication {:?}", what),
+        }
+    }
+
+    fn listen(&mut self, address: &str) -> std::io::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        println!("gdb stub listening on {}", address);
+        for stream in listener.incoming() {
+            self.handle(&mut stream?)?;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, stream: &mut TcpStream) -> std::io::Result<()> {
+        println!("New gdb connection {}", stream.peer_addr()?);
+        let result = self.serve(stream);
+        self.host.end_session();
+        result?;
+        println!("gdb disconnected");
+        Ok(())
+    }
+
+    fn serve(&mut self, stream: &mut TcpStream) -> std::io::Result<()> {
+        while let Some(packet) = recv_packet(stream)? {
+            let reply = self.handle_packet(&packet);
+            match reply {
+                Some(reply) => send_packet(&reply, stream)?,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the reply to send, None when the session should end
+    fn handle_packet(&mut self, packet: &str) -> Option<String> {
+        info!("gdb packet {}", packet);
+        if packet.is_empty() {
+            return Some(String::new());
+        }
+        let (kind, rest) = packet.split_at(1);
+        let reply = match kind {
+            "?" => self.stop_reply(),
+            "g" => self.handle_read_registers(),
+            "G" => self.handle_write_registers(rest),
+            "p" => self.handle_read_register(rest),
+            "P" => self.handle_write_register(rest),
+            "m" => self.handle_read_memory(rest),
+            "M" => self.handle_write_memory(rest),
+            "s" => self.handle_step(rest),
+            "c" => self.handle_continue(rest),
+            "Z" => self.handle_add_breakpoint(rest),
+            "z" => self.handle_remove_breakpoint(rest),
+            "H" => "OK".to_string(),
+            "T" => "OK".to_string(),
+            "q" => self.handle_query(rest),
+            "D" => {
+                return None;
+            }
+            "k" => {
+                return None;
+            }
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn handle_query(&self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            "PacketSize=4000;qXfer:features:read+".to_string()
+        } else if let Some(annex) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            match parse_addr_len(annex) {
+                Some((offset, len)) => xfer_chunk(TARGET_XML, offset, len),
+                None => "E01".to_string(),
+            }
+        } else if query == "Attached" {
+            "1".to_string()
+        } else if query == "C" {
+            "QC1".to_string()
+        } else if query == "fThreadInfo" {
+            "m1".to_string()
+        } else if query == "sThreadInfo" {
+            "l".to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    fn stop_reply(&self) -> String {
+        if self.host.is_halted() {
+            "W00".to_string()
+        } else {
+            "S05".to_string()
+        }
+    }
+
+    fn read_register(&self, regnum: usize) -> u16 {
+        match regnum {
+            PC_REGNUM => (self.host.ip() * 2) as u16,
+            _ => self.host.register(regnum),
+        }
+    }
+
+    fn write_register(&mut self, regnum: usize, value: u16) {
+        match regnum {
+            PC_REGNUM => self.host.set_ip(value as usize / 2),
+            _ => self.host.set_register(regnum, value),
+        }
+    }
+
+    fn handle_read_registers(&self) -> String {
+        (0..=PC_REGNUM)
+            .map(|reg| encode_word(self.read_register(reg)))
+            .collect()
+    }
+
+    fn handle_write_registers(&mut self, data: &str) -> String {
+        if data.len() < (PC_REGNUM + 1) * 4 {
+            return "E01".to_string();
+        }
+        for reg in 0..=PC_REGNUM {
+            match decode_word(&data[reg * 4..reg * 4 + 4]) {
+                Some(value) => self.write_register(reg, value),
+                None => return "E01".to_string(),
+            }
+        }
+        "OK".to_string()
+    }
+
+    fn handle_read_register(&self, data: &str) -> String {
+        match parse_hex(data) {
+            Some(reg) if reg <= PC_REGNUM => encode_word(self.read_register(reg)),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn handle_write_register(&mut self, data: &str) -> String {
+        let mut parts = data.splitn(2, '=');
+        let reg = parts.next().and_then(parse_hex);
+        let value = parts.next().and_then(decode_word);
+        match (reg, value) {
+            (Some(reg), Some(value)) if reg <= PC_REGNUM => {
+                self.write_register(reg, value);
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn handle_read_memory(&self, data: &str) -> String {
+        let (addr, len) = match parse_addr_len(data) {
+            Some(v) => v,
+            None => return "E01".to_string(),
+        };
+        let end = match addr.checked_add(len) {
+            Some(end) if end <= MEMORY_WORDS * 2 => end,
+            _ => return "E01".to_string(),
+        };
+        let mut retr = String::new();
+        for byte_addr in addr..end {
+            let bytes = self.host.peek(byte_addr / 2).to_le_bytes();
+            retr += &format!("{:02x}", bytes[byte_addr % 2]);
+        }
+        retr
+    }
+
+    fn handle_write_memory(&mut self, data: &str) -> String {
+        let mut parts = data.splitn(2, ':');
+        let header = parts.next().and_then(parse_addr_len);
+        let bytes = parts.next().and_then(decode_bytes);
+        let (addr, len, bytes) = match (header, bytes) {
+            (Some((addr, len)), Some(bytes)) if bytes.len() == len => (addr, len, bytes),
+            _ => return "E01".to_string(),
+        };
+        match addr.checked_add(len) {
+            Some(end) if end <= MEMORY_WORDS * 2 => (),
+            _ => return "E01".to_string(),
+        }
+        for (offset, byte) in bytes.iter().enumerate() {
+            let byte_addr = addr + offset;
+            let mut word = self.host.peek(byte_addr / 2).to_le_bytes();
+            word[byte_addr % 2] = *byte;
+            self.host.poke(byte_addr / 2, u16::from_le_bytes(word));
+        }
+        "OK".to_string()
+    }
+
+    fn resume_at(&mut self, data: &str) {
+        if let Some(addr) = parse_hex(data) {
+            self.host.set_ip(addr / 2);
+        }
+    }
+
+    fn handle_step(&mut self, data: &str) -> String {
+        self.resume_at(data);
+        if self.host.should_run() {
+            self.host.step();
+        }
+        self.stop_reply()
+    }
+
+    fn handle_continue(&mut self, data: &str) -> String {
+        self.resume_at(data);
+        // always execute at least one instruction so continuing from a
+        // breakpoint does not stop on it again
+        if self.host.should_run() {
+            self.host.step();
+        }
+        while self.host.should_run() && !self.breakpoints.contains(&self.host.ip()) {
+            self.host.step();
+        }
+        self.stop_reply()
+    }
+
+    fn parse_breakpoint(data: &str) -> Option<usize> {
+        // type,addr,kind
+        let mut parts = data.split(',');
+        if parts.next()? != "0" {
+            return None;
+        }
+        parse_hex(parts.next()?).map(|addr| addr / 2)
+    }
+
+    fn handle_add_breakpoint(&mut self, data: &str) -> String {
+        match GdbStub::parse_breakpoint(data) {
+            Some(addr) => {
+                if !self.breakpoints.contains(&addr) {
+                    self.breakpoints.push(addr);
+                }
+                "OK".to_string()
+            }
+            // only software breakpoints are supported
+            None => String::new(),
+        }
+    }
+
+    fn handle_remove_breakpoint(&mut self, data: &str) -> String {
+        match GdbStub::parse_breakpoint(data) {
+            Some(addr) => {
+                self.breakpoints.retain(|bp| *bp != addr);
+                "OK".to_string()
+            }
+            None => String::new(),
+        }
+    }
+}
+
+fn xfer_chunk(document: &str, offset: usize, len: usize) -> String {
+    if offset > document.len() {
+        return "E00".to_string();
+    }
+    let end = usize::min(offset.saturating_add(len), document.len());
+    let prefix = if end == document.len() { "l" } else { "m" };
+    format!("{}{}", prefix, &document[offset..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use code::program;
+
+    fn stub(data: Vec<u16>) -> GdbStub {
+        let mut program = program::Program::new();
+        program.data = data;
+        GdbStub {
+            host: host::Host::from(program),
+            breakpoints: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        // add r0 r1 4, out r0, halt
+        let mut gdb = stub(vec![9, 32768, 32769, 4, 19, 32768, 0]);
+        assert_eq!(gdb.handle_packet("P1=3d00").unwrap(), "OK");
+        assert_eq!(gdb.handle_packet("p1").unwrap(), "3d00");
+        assert_eq!(gdb.handle_packet("m0,4").unwrap(), "09000080");
+        assert_eq!(gdb.handle_packet("s").unwrap(), "S05");
+        assert_eq!(
+            gdb.handle_packet("g").unwrap(),
+            "41003d000000000000000000000000000800"
+        );
+        assert_eq!(gdb.handle_packet("M2,2:0100").unwrap(), "OK");
+        assert_eq!(gdb.host.peek(1), 1);
+        assert_eq!(gdb.handle_packet("mffffffffffffffff,2").unwrap(), "E01");
+        assert_eq!(gdb.handle_packet("Mffffffffffffffff,1:00").unwrap(), "E01");
+    }
+
+    #[test]
+    fn breakpoints_and_continue() {
+        let mut gdb = stub(vec![21, 21, 21, 0]);
+        assert_eq!(gdb.handle_packet("Z0,4,2").unwrap(), "OK");
+        assert_eq!(gdb.handle_packet("c").unwrap(), "S05");
+        assert_eq!(gdb.host.ip(), 2);
+        assert_eq!(gdb.handle_packet("z0,4,2").unwrap(), "OK");
+        assert_eq!(gdb.handle_packet("c").unwrap(), "W00");
+        assert_eq!(gdb.handle_packet("k"), None);
+    }
+
+    #[test]
+    fn target_description() {
+        let gdb = stub(vec![0]);
+        let reply = gdb.handle_query("Xfer:features:read:target.xml:0,ffff");
+        assert!(reply.starts_with("l<?xml"));
+        assert!(reply.contains(r#"name="pc""#));
+        let end = format!("Xfer:features:read:target.xml:{:x},1", TARGET_XML.len());
+        assert_eq!(gdb.handle_query(&end), "l");
+        let huge = "Xfer:features:read:target.xml:10,ffffffffffffffff";
+        assert!(gdb.handle_query(huge).starts_with('l'));
+        let past = "Xfer:features:read:target.xml:ffffffffffffffff,1";
+        assert_eq!(gdb.handle_query(past), "E00");
+    }
+}
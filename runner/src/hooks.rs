@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use crate::host::Host;
+
+// Native replacements for functions in the program. When the program calls
+// a hooked address the closure runs instead, reading its arguments from
+// registers or the stack and leaving results in registers, and execution
+// continues after the call as if the function had returned.
+
+pub type HookHandler = Box<dyn FnMut(&mut Host) + Send>;
+
+struct Hook {
+    handler: HookHandler,
+    // registers on entry => registers on return, only for hooks that
+    // depend on nothing but registers
+    memo: Option<HashMap<[u16; 8], [u16; 8]>>,
+}
+
+#[derive(Default)]
+pub struct Hooks {
+    hooks: HashMap<usize, Hook>,
+}
+
+impl Hooks {
+    /// Replaces calls to `address` with `handler`. With `memoize` the
+    /// registers it returns are remembered per set of registers it was
+    /// called with and the handler only runs for new ones.
+    pub fn add(&mut self, address: usize, memoize: bool, handler: HookHandler) {
+        let memo = if memoize { Some(HashMap::new()) } else { None };
+        self.hooks.insert(address, Hook { handler, memo });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    pub fn contains(&self, address: usize) -> bool {
+        self.hooks.contains_key(&address)
+    }
+
+    /// Runs the hook for `address`, false if there is none
+    pub fn call(&mut self, address: usize, host: &mut Host) -> bool {
+        let hook = match self.hooks.get_mut(&address) {
+            Some(hook) => hook,
+            None => return false,
+        };
+        let registers = host.registers();
+        if let Some(result) = hook.memo.as_ref().and_then(|m| m.get(&registers)) {
+            for (reg, value) in result.iter().enumerate() {
+                host.set_register(reg, *value);
+            }
+            return true;
+        }
+        (hook.handler)(host);
+        if let Some(memo) = hook.memo.as_mut() {
+            memo.insert(registers, host.registers());
+        }
+        true
+    }
+}
+
+/// The teleporter confirmation routine, an Ackermann function where
+/// A(m, 0) = A(m - 1, reg7), computed bottom up instead of recursively.
+/// Takes m in reg0 and n in reg1, returns the result in reg0 and leaves
+/// reg1 the way the recursive version does.
+pub fn teleporter(host: &mut Host) {
+    let m = host.register(0) as usize;
+    let n = host.register(1) as usize;
+    let r7 = host.register(7) as usize;
+    // row[n] = A(level, n)
+    let mut row: Vec<usize> = (0..32768).map(|n| (n + 1) % 32768).collect();
+    for _ in 0..m {
+        let mut next = vec![0; 32768];
+        next[0] = row[r7];
+        for idx in 1..32768 {
+            next[idx] = row[next[idx - 1]];
+        }
+        row = next;
+    }
+    let result = row[n] as u16;
+    host.set_register(0, result);
+    host.set_register(1, (result + 32767) % 32768);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{Limits, Outcome};
+    use code::program::Program;
+    use std::sync::{Arc, Mutex};
+
+    // the recursive routine from the challenge, moved to address 6
+    // 0: call 6, 2: halt
+    const PROGRAM: [u16; 47] = [
+        17, 6, 0, 0, 0, 0, // call, halt, padding
+        7, 32768, 14, // 6: jt r0 14
+        9, 32768, 32769, 1, // 9: add r0 r1 1
+        18, // 13: ret
+        7, 32769, 27, // 14: jt r1 27
+        9, 32768, 32768, 32767, // 17: add r0 r0 32767
+        1, 32769, 32775, // 21: set r1 r7
+        17, 6, // 24: call 6
+        18, // 26: ret
+        2, 32768, // 27: push r0
+        9, 32769, 32769, 32767, // 29: add r1 r1 32767
+        17, 6, // 33: call 6
+        1, 32769, 32768, // 35: set r1 r0
+        3, 32768, // 38: pop r0
+        9, 32768, 32768, 32767, // 40: add r0 r0 32767
+        17, 6, // 44: call 6
+        18, // 46: ret
+    ];
+
+    fn host(m: u16, n: u16, r7: u16) -> Host {
+        let mut program = Program::new();
+        program.data = PROGRAM.to_vec();
+        let mut host = Host::from(program);
+        host.set_register(0, m);
+        host.set_register(1, n);
+        host.set_register(7, r7);
+        host
+    }
+
+    #[test]
+    fn native_teleporter_matches_the_vm() {
+        for (m, n, r7) in [(0, 5, 1), (1, 3, 2), (2, 2, 3), (3, 1, 1)].iter() {
+            let mut vm = host(*m, *n, *r7);
+            assert_eq!(vm.run(&Limits::default()), Outcome::Halted);
+
+            let mut native = host(*m, *n, *r7);
+            native.hooks.add(6, false, Box::new(teleporter));
+            assert_eq!(native.run(&Limits::default()), Outcome::Halted);
+            assert_eq!(native.registers(), vm.registers());
+            assert_eq!(native.stack(), vm.stack());
+        }
+    }
+
+    #[test]
+    fn memoized_hook_runs_once() {
+        let calls = Arc::new(Mutex::new(0));
+        let counter = calls.clone();
+        let mut hooks = Hooks::default();
+        hooks.add(
+            6,
+            true,
+            Box::new(move |host| {
+                *counter.lock().unwrap() += 1;
+                host.set_register(0, 42);
+            }),
+        );
+        let mut host = host(1, 2, 3);
+        assert!(hooks.call(6, &mut host));
+        host.set_register(0, 1);
+        assert!(hooks.call(6, &mut host));
+        assert_eq!(host.register(0), 42);
+        assert_eq!(*calls.lock().unwrap(), 1);
+        assert!(!hooks.call(7, &mut host));
+    }
+}
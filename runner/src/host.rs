@@ -1,12 +1,57 @@
 use std::collections::vec_deque;
 use std::io;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time;
 
-use log::{debug, error, info};
+use log::{debug, error, info, log_enabled, Level};
 
 use messages::VmState;
 
-use code::opcodes::OpCodes;
+use code::opcodes::{OpCodes, Role};
 use code::program::Program;
+use code::trace::{TraceRecord, TraceWrite};
+
+use crate::coverage::Coverage;
+use crate::hooks::Hooks;
+use crate::input::Input;
+use crate::loops::LoopDetector;
+use crate::meta::MetaCommands;
+use crate::profiler::Profiler;
+use crate::tracer::Tracer;
+
+/// Why Host::run returned
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Halted,
+    Fault(String),
+    EndOfInput,
+    InstructionLimit,
+    Timeout,
+    /// the program can never stop, with the addresses that make up the loop
+    LoopDetected(Vec<usize>),
+    /// Limits::interrupt was set, by Ctrl-C
+    Interrupted,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    pub max_instructions: Option<u64>,
+    pub timeout: Option<time::Duration>,
+    pub detect_loops: bool,
+    /// checked along with the timeout, run stops once it is set
+    pub interrupt: Option<Arc<AtomicBool>>,
+}
+
+/// An instruction decoded ahead of time for the fast path, only instructions
+/// with valid operands are cached
+#[derive(Clone)]
+struct Decoded {
+    op: OpCodes,
+    args: [u16; 3],
+    next: usize,
+}
 
 pub struct Host {
     registers: [u16; 8],
@@ -14,19 +59,37 @@ pub struct Host {
     memory: Vec<u16>,
     ip: usize,
     input_buffer: vec_deque::VecDeque<u16>,
+    pub input: Input,
+    pub meta: MetaCommands,
+    pub hooks: Hooks,
     halted: bool,
-    count: u32, //number of instructions execued,
+    // why the program stopped when it did not halt by itself
+    fault: Option<String>,
+    input_closed: bool,
+    // characters handed to `in` so far
+    input_read: u64,
+    output: Box<dyn Write + Send>,
+    // bumped on every change to memory, registers or stack
+    generation: u64,
+    /// use the decoded instruction cache instead of the logging interpreter
+    pub fast: bool,
+    decoded: Vec<Option<Decoded>>,
+    // address of the instruction being executed
+    current: usize,
+    count: u64, //number of instructions execued,
     pub program: Program,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    // writes done by the current instruction, collected while tracing
+    trace_writes: Option<Vec<TraceWrite>>,
 }
 
 fn is_memory(address: u16) -> bool {
-    match address {
-        0..=32767 => true,
-        32768..=32775 => false,
-        _ => panic!("invalid address: {}", address),
-    }
+    address < 32768
 }
 
+/// Registers and invalid values, which fault when resolved
 fn is_register(address: u16) -> bool {
     !is_memory(address)
 }
@@ -39,9 +102,25 @@ impl Host {
             memory: Vec::new(),
             ip: 0,
             halted: false,
+            fault: None,
+            input_closed: false,
+            input_read: 0,
+            output: Box::new(io::stdout()),
+            generation: 0,
+            // debug logging needs the slow path
+            fast: !log_enabled!(Level::Debug),
+            decoded: Vec::new(),
+            current: 0,
             input_buffer: vec_deque::VecDeque::new(),
+            input: Input::new(),
+            meta: MetaCommands::builtin(),
+            hooks: Hooks::default(),
             count: 0,
             program: Program::new(),
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            trace_writes: None,
         }
     }
     pub fn from(program: Program) -> Host {
@@ -59,34 +138,198 @@ impl Host {
         self.ip
     }
 
-    pub fn create_state(&self) -> VmState {
-        VmState::from(
-            self.registers,
+    pub fn set_ip(&mut self, ip: usize) {
+        self.ip = ip;
+    }
+
+    pub fn register(&self, reg: usize) -> u16 {
+        self.registers[reg]
+    }
+
+    pub fn set_register(&mut self, reg: usize, value: u16) {
+        self.registers[reg] = value;
+    }
+
+    pub fn registers(&self) -> [u16; 8] {
+        self.registers
+    }
+
+    /// Reads a memory word, addresses past the loaded image read as 0
+    pub fn peek(&self, address: usize) -> u16 {
+        match self.memory.get(address) {
+            Some(val) => *val,
+            None => 0,
+        }
+    }
+
+    /// Writes a memory word, growing memory if needed
+    pub fn poke(&mut self, address: usize, value: u16) {
+        if address >= self.memory.len() {
+            self.memory.resize(address + 1, 0);
+        }
+        self.memory[address] = value;
+        self.invalidate(address);
+    }
+
+    /// The instruction at ip
+    pub fn current_op(&self) -> OpCodes {
+        OpCodes::parse(self.peek(self.ip))
+    }
+
+    /// Where execution resumes after the call at ip returns, None if the
+    /// instruction at ip is not a call
+    pub fn call_return_address(&self) -> Option<usize> {
+        match self.current_op() {
+            OpCodes::call => Some(self.ip + 1 + OpCodes::call.argcount()),
+            _ => None,
+        }
+    }
+
+    pub fn stack(&self) -> &[u16] {
+        &self.stack
+    }
+
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    /// Where the program's `out` goes, stdout by default
+    pub fn set_output(&mut self, output: Box<dyn Write + Send>) {
+        self.output = output;
+    }
+
+    pub fn set_coverage(&mut self, coverage: Option<Coverage>) {
+        self.coverage = coverage;
+    }
+
+    /// Turns an installed tracer on or off, false if there is none
+    pub fn tracing(&mut self, enabled: bool) -> bool {
+        match self.tracer.as_mut() {
+            Some(tracer) => {
+                tracer.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.memory.len()
+    }
+
+    /// Vm state as text, see restore
+    pub fn snapshot(&self) -> String {
+        let join = |values: &[u16]| {
+            values
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<String>>()
+                .join(" ")
+        };
+        format!(
+            "ip {}\ncount {}\nregisters {}\nstack {}\nmemory {}\n",
             self.ip,
             self.count,
-            &self.memory[self.ip..self.ip + 20],
+            join(&self.registers),
+            join(&self.stack),
+            join(&self.memory)
         )
     }
 
-    pub fn create_memory_dump(&self, address : usize, len : usize) -> Vec<u16> {
-        Vec::from(&self.memory[address..address+len])
+    pub fn restore(&mut self, text: &str) -> Result<(), String> {
+        let mut fields = std::collections::HashMap::new();
+        let mut count = None;
+        for line in text.lines() {
+            let (name, values) = line.split_once(' ').unwrap_or((line, ""));
+            // the one field that doesn't fit in a word
+            if name == "count" {
+                let value = values
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|e| format!("invalid count: {}", e))?;
+                count = Some(value);
+                continue;
+            }
+            let values = values
+                .split_whitespace()
+                .map(|v| v.parse::<u16>())
+                .collect::<Result<Vec<u16>, _>>()
+                .map_err(|e| format!("invalid {}: {}", name, e))?;
+            fields.insert(name, values);
+        }
+        let mut field = |name: &str| {
+            fields
+                .remove(name)
+                .ok_or_else(|| format!("snapshot is missing {}", name))
+        };
+        let ip = field("ip")?;
+        let registers = field("registers")?;
+        let stack = field("stack")?;
+        let memory = field("memory")?;
+        let count = count.ok_or("snapshot is missing count")?;
+        if ip.len() != 1 || registers.len() != 8 {
+            return Err("malformed snapshot".to_string());
+        }
+        self.ip = ip[0] as usize;
+        self.count = count;
+        self.registers.copy_from_slice(&registers);
+        self.stack = stack;
+        self.memory = memory;
+        self.decoded.clear();
+        self.input_buffer.clear();
+        self.halted = false;
+        self.fault = None;
+        self.input_closed = false;
+        Ok(())
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn create_state(&self) -> VmState {
+        let here: Vec<u16> = (self.ip..self.ip + 20).map(|a| self.peek(a)).collect();
+        VmState::from(self.registers, self.ip, self.count, &here)
+    }
+
+    pub fn create_memory_dump(&self, address: usize, len: usize) -> Vec<u16> {
+        (address..address + len).map(|a| self.peek(a)).collect()
     }
 
-    fn resolve(&self, value: u16) -> u16 {
+    /// Operand `index` of the instruction whose opcode is just before ip
+    fn arg(&self, index: usize) -> Result<u16, String> {
+        match self.memory.get(self.ip + index) {
+            Some(val) => Ok(*val),
+            None => Err("instruction cut off by the end of memory".to_string()),
+        }
+    }
+
+    fn resolve(&self, value: u16) -> Result<u16, String> {
         match value {
-            0..=32767 => value,
+            0..=32767 => Ok(value),
             32768..=32775 => {
                 let reg = value - 32768;
                 debug!(
                     "  resolved {} => reg{} => {}",
                     value, reg, self.registers[reg as usize]
                 );
-                self.registers[reg as usize]
+                Ok(self.registers[reg as usize])
             }
-            _ => panic!("invalid value {}", value),
+            _ => Err(format!("invalid value {}", value)),
         }
     }
-    fn write(&mut self, address: u16, value: u16) {
+    fn write(&mut self, address: u16, value: u16) -> Result<(), String> {
+        self.generation += 1;
+        if let Some(writes) = self.trace_writes.as_mut() {
+            writes.push(TraceWrite {
+                target: address,
+                value,
+            });
+        }
         match address {
             0..=32767 => {
                 if address as usize >= self.memory.len() {
@@ -95,40 +338,36 @@ impl Host {
                 }
                 debug!("  write memory[{}] = {}", address, value);
                 self.memory[address as usize] = value;
+                self.invalidate(address as usize);
             }
             32768..=32775 => {
                 let reg = address - 32768;
-                if reg > 7 {
-                    panic!("invalid register {}", reg);
-                }
                 debug!("  write reg[{}] = {}", reg, value);
                 self.registers[reg as usize] = value;
             }
-            _ => panic!("invalid write target: {}", address),
+            _ => return Err(format!("invalid write target: {}", address)),
         }
+        Ok(())
     }
 
-    fn read(&self, address: u16) -> u16 {
+    fn read(&self, address: u16) -> Result<u16, String> {
         match address {
             0..=32767 => {
                 if address as usize >= self.memory.len() {
-                    panic!("read outsize of memory");
+                    return Err(format!("read outside of memory: {}", address));
                 }
                 debug!(
                     "  read memory[{}] => {}",
                     address, self.memory[address as usize]
                 );
-                self.memory[address as usize]
+                Ok(self.memory[address as usize])
             }
             32768..=32775 => {
                 let reg = address - 32768;
-                if reg > 7 {
-                    panic!("invalid register {}", reg);
-                }
                 debug!("  read reg[{}] = {}", reg, self.registers[reg as usize]);
-                self.registers[reg as usize]
+                Ok(self.registers[reg as usize])
             }
-            _ => panic!("invalid read target: {}", address),
+            _ => Err(format!("invalid read target: {}", address)),
         }
     }
 
@@ -136,19 +375,350 @@ impl Host {
         self.ip < self.memory.len() && !self.halted
     }
 
-    pub fn run(&mut self) {
+    /// Runs until the program stops or a limit is reached
+    pub fn run(&mut self, limits: &Limits) -> Outcome {
         self.halted = false;
+        let stopped = self.run_loop(limits);
+        // a halt, faults included, already wrote everything out
+        if !self.halted {
+            self.finish();
+        }
+        match (stopped, &self.fault, self.input_closed) {
+            (Some(outcome), _, _) => outcome,
+            (None, Some(what), _) => Outcome::Fault(what.clone()),
+            (None, None, true) => Outcome::EndOfInput,
+            (None, None, false) => Outcome::Halted,
+        }
+    }
+
+    /// Steps until the program stops, Some when a limit stopped it
+    fn run_loop(&mut self, limits: &Limits) -> Option<Outcome> {
+        let start = time::Instant::now();
+        let mut executed: u64 = 0;
+        let mut detector = if limits.detect_loops {
+            Some(LoopDetector::new())
+        } else {
+            None
+        };
         while self.should_run() {
+            if let Some(detector) = detector.as_mut() {
+                let found = detector.check(
+                    self.ip,
+                    self.generation,
+                    self.input_read,
+                    &self.registers,
+                    &self.stack,
+                    &self.memory,
+                );
+                if let Some(addresses) = found {
+                    return Some(Outcome::LoopDetected(addresses));
+                }
+            }
+            if limits.max_instructions.is_some_and(|max| executed >= max) {
+                return Some(Outcome::InstructionLimit);
+            }
+            // checking the clock every instruction is too slow
+            if executed % 10000 == 0 {
+                if limits.timeout.is_some_and(|t| start.elapsed() >= t) {
+                    return Some(Outcome::Timeout);
+                }
+                if let Some(interrupt) = &limits.interrupt {
+                    if interrupt.load(Ordering::Relaxed) {
+                        return Some(Outcome::Interrupted);
+                    }
+                }
+            }
+            if detector.is_none() && self.uninstrumented() {
+                // up to the next clock check without going through step
+                let mut chunk = 10000 - executed % 10000;
+                if let Some(max) = limits.max_instructions {
+                    chunk = u64::min(chunk, max - executed);
+                }
+                let done = self.run_fast(chunk);
+                executed += done;
+                if done == chunk {
+                    continue;
+                }
+            }
             self.step();
+            executed += 1;
+        }
+        None
+    }
+
+    fn uninstrumented(&self) -> bool {
+        self.fast && self.tracer.is_none() && self.profiler.is_none() && self.coverage.is_none()
+    }
+
+    /// Executes up to `count` instructions on the fast path and returns how
+    /// many ran, stops early at anything the fast path can't handle
+    fn run_fast(&mut self, count: u64) -> u64 {
+        let mut done = 0;
+        while done < count && self.ip < self.memory.len() && self.execute_fast() {
+            done += 1;
+        }
+        done
+    }
+
+    fn profile(&mut self) {
+        let opcode = self.peek(self.ip);
+        let target = match OpCodes::parse(opcode) {
+            OpCodes::call => match self.peek(self.ip + 1) {
+                reg @ 32768..=32775 => Some(self.registers[(reg - 32768) as usize] as usize),
+                address => Some(address as usize),
+            },
+            _ => None,
+        };
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record(self.ip, opcode, target);
+        }
+    }
+
+    /// Captures the instruction at ip before it executes
+    fn begin_trace(&mut self) -> TraceRecord {
+        let opcode = self.peek(self.ip);
+        let argcount = OpCodes::parse(opcode).argcount();
+        let operands: Vec<u16> = (1..=argcount).map(|a| self.peek(self.ip + a)).collect();
+        let values = operands
+            .iter()
+            .map(|o| match o {
+                32768..=32775 => self.registers[(o - 32768) as usize],
+                _ => *o,
+            })
+            .collect();
+        self.trace_writes = Some(Vec::new());
+        TraceRecord {
+            count: self.count,
+            ip: self.ip as u16,
+            opcode,
+            operands,
+            values,
+            writes: Vec::new(),
         }
     }
 
     pub fn step(&mut self) {
+        if self.profiler.is_some() {
+            self.profile();
+        }
+        let record = if self.tracer.is_some() {
+            Some(self.begin_trace())
+        } else {
+            None
+        };
+        let ip = self.ip;
+        self.current = ip;
+        let branch = if self.coverage.is_some() {
+            self.branch_taken()
+        } else {
+            None
+        };
+        if !self.hooks.is_empty() && self.call_hook() {
+            self.count += 1;
+        } else if !(self.fast && record.is_none() && self.execute_fast()) {
+            self.execute();
+        }
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.record(ip, branch);
+        }
+        if let Some(mut record) = record {
+            record.writes = self.trace_writes.take().unwrap_or_default();
+            if let Some(tracer) = self.tracer.as_mut() {
+                tracer.record(&record);
+            }
+        }
+        if self.halted {
+            self.finish();
+        }
+    }
+
+    /// Whether the jt or jf at ip is going to jump, None for anything else.
+    /// The condition decides, the target can be the next instruction.
+    fn branch_taken(&self) -> Option<bool> {
+        let condition = match self.peek(self.ip + 1) {
+            value @ 0..=32767 => value,
+            reg @ 32768..=32775 => self.registers[(reg - 32768) as usize],
+            _ => return None,
+        };
+        match self.current_op() {
+            OpCodes::jt => Some(condition != 0),
+            OpCodes::jf => Some(condition == 0),
+            _ => None,
+        }
+    }
+
+    /// Runs the hook for the call at ip instead of calling, false if the
+    /// instruction isn't a call to a hooked address
+    fn call_hook(&mut self) -> bool {
+        if self.current_op() != OpCodes::call {
+            return false;
+        }
+        let target = match self.peek(self.ip + 1) {
+            reg @ 32768..=32775 => self.registers[(reg - 32768) as usize] as usize,
+            address => address as usize,
+        };
+        if !self.hooks.contains(target) {
+            return false;
+        }
+        debug!("  hooked call to {}", target);
+        let next = self.ip + 1 + OpCodes::call.argcount();
+        let mut hooks = std::mem::take(&mut self.hooks);
+        hooks.call(target, self);
+        self.hooks = hooks;
+        self.generation += 1;
+        self.ip = next;
+        true
+    }
+
+    fn decode(&self, ip: usize) -> Option<Decoded> {
+        let op = OpCodes::parse(*self.memory.get(ip)?);
+        let info = op.info()?;
+        let mut args = [0; 3];
+        for (idx, (arg, role)) in args.iter_mut().zip(info.operands).enumerate() {
+            *arg = *self.memory.get(ip + 1 + idx)?;
+            // bad operands fault on the slow path
+            let valid = match role {
+                Role::Destination => (32768..=32775).contains(arg),
+                _ => *arg <= 32775,
+            };
+            if !valid {
+                return None;
+            }
+        }
+        let next = ip + 1 + op.argcount();
+        Some(Decoded { op, args, next })
+    }
+
+    /// Drops cached instructions that have `address` as opcode or operand
+    fn invalidate(&mut self, address: usize) {
+        let from = address.saturating_sub(3);
+        let to = usize::min(address + 1, self.decoded.len());
+        for entry in self.decoded.iter_mut().take(to).skip(from) {
+            *entry = None;
+        }
+    }
+
+    #[inline]
+    fn value(&self, operand: u16) -> u16 {
+        if operand < 32768 {
+            operand
+        } else {
+            self.registers[(operand - 32768) as usize]
+        }
+    }
+
+    /// write without logging or tracing
+    #[inline]
+    fn store(&mut self, target: u16, value: u16) {
+        self.generation += 1;
+        if target >= 32768 {
+            self.registers[(target - 32768) as usize] = value;
+            return;
+        }
+        let address = target as usize;
+        if address >= self.memory.len() {
+            self.memory.resize(address + 1, 0);
+        }
+        self.memory[address] = value;
+        self.invalidate(address);
+    }
+
+    /// Executes the instruction at ip from the decoded cache. Returns false,
+    /// without doing anything, for instructions the slow path has to handle.
+    fn execute_fast(&mut self) -> bool {
+        let ip = self.ip;
+        let inst = match self.decoded.get(ip) {
+            Some(Some(inst)) => inst.clone(),
+            _ => match self.decode(ip) {
+                Some(inst) => {
+                    if self.decoded.len() < self.memory.len() {
+                        self.decoded.resize(self.memory.len(), None);
+                    }
+                    self.decoded[ip] = Some(inst.clone());
+                    inst
+                }
+                None => return false,
+            },
+        };
+        let [a, b, c] = inst.args;
+        let mut next = inst.next;
+        match inst.op {
+            OpCodes::set => self.store(a, self.value(b)),
+            OpCodes::push => {
+                self.stack.push(self.value(a));
+                self.generation += 1;
+            }
+            OpCodes::pop => match self.stack.pop() {
+                Some(val) => self.store(a, val),
+                None => return false,
+            },
+            OpCodes::eq => self.store(a, (self.value(b) == self.value(c)) as u16),
+            OpCodes::gt => self.store(a, (self.value(b) > self.value(c)) as u16),
+            OpCodes::jmp => next = self.value(a) as usize,
+            OpCodes::jt => {
+                if self.value(a) != 0 {
+                    next = self.value(b) as usize;
+                }
+            }
+            OpCodes::jf => {
+                if self.value(a) == 0 {
+                    next = self.value(b) as usize;
+                }
+            }
+            OpCodes::add => self.store(a, (self.value(b) + self.value(c)) % 32768),
+            OpCodes::mult => {
+                let val = self.value(b) as u32 * self.value(c) as u32 % 32768;
+                self.store(a, val as u16)
+            }
+            OpCodes::mod_ => match self.value(c) {
+                0 => return false,
+                val => self.store(a, self.value(b) % val),
+            },
+            OpCodes::and => self.store(a, self.value(b) & self.value(c)),
+            OpCodes::or => self.store(a, self.value(b) | self.value(c)),
+            OpCodes::not => self.store(a, !self.value(b) & 0x7fff),
+            OpCodes::rmem => match self.memory.get(self.value(b) as usize) {
+                Some(val) => self.store(a, *val),
+                _ => return false,
+            },
+            OpCodes::wmem => match self.value(a) {
+                target if target < 32768 => self.store(target, self.value(b)),
+                _ => return false,
+            },
+            OpCodes::call => match self.value(a) {
+                0 => return false,
+                target if self.hooks.contains(target as usize) => return false,
+                target => {
+                    self.stack.push(next as u16);
+                    self.generation += 1;
+                    next = target as usize;
+                }
+            },
+            OpCodes::ret => match self.stack.pop() {
+                Some(val) => {
+                    self.generation += 1;
+                    next = val as usize;
+                }
+                None => return false,
+            },
+            OpCodes::nop => {}
+            // io and halt are rare, leave them to the slow path
+            _ => return false,
+        }
+        self.ip = next;
+        self.count += 1;
+        true
+    }
+
+    fn execute(&mut self) {
         let inst = OpCodes::parse(self.memory[self.ip]);
         debug!("{}/{}, {:?}", self.ip, self.count, inst);
         self.ip += 1;
-        match inst {
-            OpCodes::halt => self.exec_halt(),
+        let result = match inst {
+            OpCodes::halt => {
+                self.exec_halt();
+                Ok(())
+            }
             OpCodes::set => self.exec_set(),
             OpCodes::push => self.exec_push(),
             OpCodes::pop => self.exec_pop(),
@@ -169,295 +739,473 @@ impl Host {
             OpCodes::ret => self.exec_ret(),
             OpCodes::out => self.exec_out(),
             OpCodes::in_ => self.exec_in(),
-            OpCodes::nop => {}
-            OpCodes::unknown(val) => {
-                self.exec_halt();
-                panic!("unknown instruction {:?}", val)
-            }
+            OpCodes::nop => Ok(()),
+            OpCodes::unknown(val) => Err(format!("unknown instruction {}", val)),
+        };
+        if let Err(what) = result {
+            self.fault(what);
         }
         self.count += 1;
     }
 
+    /// Called when a debugger session ends. Servers are usually stopped by
+    /// killing them, so this is the last chance to save profiles and
+    /// coverage; the vm stays where it is for the next session.
+    pub fn end_session(&mut self) {
+        self.finish();
+    }
+
+    /// Writes out everything collected while running
+    pub fn finish(&mut self) {
+        if let Err(what) = self.output.flush() {
+            error!("failed to flush output: {}", what);
+        }
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.flush();
+        }
+        if let Some(profiler) = &self.profiler {
+            profiler.save();
+        }
+        if let Some(coverage) = &self.coverage {
+            coverage.save();
+        }
+    }
+
+    /// Stops the vm because the program did something invalid
+    fn fault(&mut self, what: String) {
+        error!("  fault at {}: {}", self.current, what);
+        self.fault = Some(what);
+        self.exec_halt();
+    }
+
     fn exec_halt(&mut self) {
-        self.halted = false;
-        info!("  end memory: {:?}", &self.memory[844]);
+        self.halted = true;
+        info!("  end memory: {:?}", self.peek(844));
         info!("  registers {:?}", self.registers);
         info!("  halt");
     }
 
-    fn exec_set(&mut self) {
-        let a = self.memory[self.ip];
-        let mut b = self.memory[self.ip + 1];
+    fn exec_set(&mut self) -> Result<(), String> {
+        let a = self.arg(0)?;
+        let mut b = self.arg(1)?;
         debug!("  set a {} b {}", a, b);
         if is_register(b) {
-            b = self.resolve(b);
+            b = self.resolve(b)?;
         }
 
         debug!("  set reg[{}] = {}", a, b);
-        self.write(a, b);
+        self.write(a, b)?;
         self.ip += 2;
+        Ok(())
     }
 
-    fn exec_push(&mut self) {
-        let a = self.memory[self.ip];
-        let ra = self.resolve(a);
+    fn exec_push(&mut self) -> Result<(), String> {
+        let a = self.arg(0)?;
+        let ra = self.resolve(a)?;
         debug!(" push a {} ra {}", a, ra);
         self.stack.push(ra);
+        self.generation += 1;
         self.ip += 1;
+        Ok(())
     }
 
-    fn exec_pop(&mut self) {
+    fn exec_pop(&mut self) -> Result<(), String> {
         if self.stack.is_empty() {
-            error!(" pop empty stack");
-            self.exec_halt();
+            return Err("pop from empty stack".to_string());
         }
+        let a = self.arg(0)?;
         let val = self.stack.pop();
-        let a = self.memory[self.ip];
+        self.generation += 1;
         debug!(" pop a {}", a);
-        self.write(a, val.unwrap());
+        self.write(a, val.unwrap())?;
         self.ip += 1;
+        Ok(())
     }
 
-    fn exec_eq(&mut self) {
-        let a = self.memory[self.ip];
-        let mut b = self.memory[self.ip + 1];
-        let mut c = self.memory[self.ip + 2];
+    fn exec_eq(&mut self) -> Result<(), String> {
+        let a = self.arg(0)?;
+        let mut b = self.arg(1)?;
+        let mut c = self.arg(2)?;
         debug!("  eq a {}, b {}, c {}", a, b, c);
-        b = self.resolve(b);
-        c = self.resolve(c);
+        b = self.resolve(b)?;
+        c = self.resolve(c)?;
         debug!("  eq final a {} b {} c {}", a, b, c);
         if b == c {
             debug!("  eq reg[{}] = 1", a);
-            self.write(a, 1);
+            self.write(a, 1)?;
         } else {
             debug!("  eq reg[{}] = 0", a);
-            self.write(a, 0);
+            self.write(a, 0)?;
         }
         self.ip += 3;
+        Ok(())
     }
 
-    fn exec_gt(&mut self) {
-        let a = self.memory[self.ip];
-        let mut b = self.memory[self.ip + 1];
-        let mut c = self.memory[self.ip + 2];
+    fn exec_gt(&mut self) -> Result<(), String> {
+        let a = self.arg(0)?;
+        let mut b = self.arg(1)?;
+        let mut c = self.arg(2)?;
         debug!("  gt a {}, b {}, c {}", a, b, c);
-        b = self.resolve(b);
-        c = self.resolve(c);
+        b = self.resolve(b)?;
+        c = self.resolve(c)?;
         debug!("  gt final a {} b {} c {}", a, b, c);
         if b > c {
             debug!("  gt write [{}] = 1", a);
-            self.write(a, 1);
+            self.write(a, 1)?;
         } else {
             debug!("  gt write [{}] = 0", a);
-            self.write(a, 0);
+            self.write(a, 0)?;
         }
         self.ip += 3;
+        Ok(())
     }
 
-    fn exec_jmp(&mut self) {
-        let a = self.memory[self.ip];
-        debug!("  at {}, jmp to {:?}", self.ip, a);
-        self.ip = a as usize;
+    fn exec_jmp(&mut self) -> Result<(), String> {
+        let a = self.arg(0)?;
+        let ra = self.resolve(a)?;
+        debug!("  at {}, jmp to {:?}", self.ip, ra);
+        self.ip = ra as usize;
+        Ok(())
     }
 
-    fn exec_jt(&mut self) {
-        let a = self.memory[self.ip];
-        let ra = self.resolve(a);
-        let b = self.memory[self.ip + 1];
-        let rb = self.resolve(b);
+    fn exec_jt(&mut self) -> Result<(), String> {
+        let a = self.arg(0)?;
+        let ra = self.resolve(a)?;
+        let b = self.arg(1)?;
+        let rb = self.resolve(b)?;
         debug!("  jt a {} ra {} b {} rb {}", a, ra, b, rb);
         if ra != 0 {
-            debug!("  at {}, jt to {:?}", self.ip, b);
-            self.ip = b as usize;
+            debug!("  at {}, jt to {:?}", self.ip, rb);
+            self.ip = rb as usize;
         } else {
             self.ip += 2;
         }
+        Ok(())
     }
 
-    fn exec_jf(&mut self) {
-        let a = self.memory[self.ip];
-        let ra = self.resolve(a);
-        let b = self.memory[self.ip + 1];
-        debug!("  jf a {} ra {} b {}", a, ra, b);
+    fn exec_jf(&mut self) -> Result<(), String> {
+        let a = self.arg(0)?;
+        let ra = self.resolve(a)?;
+        let b = self.arg(1)?;
+        let rb = self.resolve(b)?;
+        debug!("  jf a {} ra {} b {} rb {}", a, ra, b, rb);
         if ra == 0 {
-            debug!("  at {}, jf to {:?}", self.ip, b);
-            self.ip = b as usize;
+            debug!("  at {}, jf to {:?}", self.ip, rb);
+            self.ip = rb as usize;
         } else {
             self.ip += 2;
         }
+        Ok(())
     }
 
-    fn exec_add(&mut self) {
-        let a = self.memory[self.ip];
-        let mut b = self.memory[self.ip + 1];
-        let mut c = self.memory[self.ip + 2];
+    fn exec_add(&mut self) -> Result<(), String> {
+        let a = self.arg(0)?;
+        let mut b = self.arg(1)?;
+        let mut c = self.arg(2)?;
         debug!("  add a {}, b {}, c {}", a, b, c);
-        b = self.resolve(b);
-        c = self.resolve(c);
+        b = self.resolve(b)?;
+        c = self.resolve(c)?;
         debug!("  add final a {}, b {}, c {}", a, b, c);
-        self.write(a, (b + c) % 32768);
-        self.ip += 3
+        self.write(a, ((b as u32 + c as u32) % 32768) as u16)?;
+        self.ip += 3;
+        Ok(())
     }
 
-    fn exec_mult(&mut self) {
-        let a = self.memory[self.ip];
-        let mut b = self.memory[self.ip + 1];
-        let mut c = self.memory[self.ip + 2];
+    fn exec_mult(&mut self) -> Result<(), String> {
+        let a = self.arg(0)?;
+        let mut b = self.arg(1)?;
+        let mut c = self.arg(2)?;
         debug!("  mult a {}, b {}, c {}", a, b, c);
-        b = self.resolve(b);
-        c = self.resolve(c);
+        b = self.resolve(b)?;
+        c = self.resolve(c)?;
         debug!("  mult final a {}, b {}, c {}", a, b, c);
         let large = b as u32 * c as u32;
         let bounded = large % 32768;
-        self.write(a, bounded as u16);
-        self.ip += 3
+        self.write(a, bounded as u16)?;
+        self.ip += 3;
+        Ok(())
     }
 
-    fn exec_mod(&mut self) {
-        let a = self.memory[self.ip];
-        let mut b = self.memory[self.ip + 1];
-        let mut c = self.memory[self.ip + 2];
+    fn exec_mod(&mut self) -> Result<(), String> {
+        let a = self.arg(0)?;
+        let mut b = self.arg(1)?;
+        let mut c = self.arg(2)?;
         debug!("  mod a {}, b {}, c {}", a, b, c);
-        b = self.resolve(b);
-        c = self.resolve(c);
+        b = self.resolve(b)?;
+        c = self.resolve(c)?;
         debug!("  mod final a {}, b {}, c {}", a, b, c);
-        self.write(a, (b % c) % 32768);
-        self.ip += 3
+        if c == 0 {
+            return Err("mod by zero".to_string());
+        }
+        self.write(a, (b % c) % 32768)?;
+        self.ip += 3;
+        Ok(())
     }
 
-    fn exec_and(&mut self) {
-        let a = self.memory[self.ip];
-        let b = self.memory[self.ip + 1];
-        let c = self.memory[self.ip + 2];
+    fn exec_and(&mut self) -> Result<(), String> {
+        let a = self.arg(0)?;
+        let b = self.arg(1)?;
+        let c = self.arg(2)?;
         debug!("  and a {} b {} c {}", a, b, c);
-        let rb = self.resolve(b);
-        let rc = self.resolve(c);
+        let rb = self.resolve(b)?;
+        let rc = self.resolve(c)?;
         debug!("  and rb {}, rc {}", rb, rc);
 
         let val = rb & rc;
         debug!("  and reg[{}] = {}", a, val);
-        self.write(a, val);
+        self.write(a, val)?;
 
         self.ip += 3;
+        Ok(())
     }
 
-    fn exec_or(&mut self) {
-        let a = self.memory[self.ip];
-        let b = self.memory[self.ip + 1];
-        let c = self.memory[self.ip + 2];
+    fn exec_or(&mut self) -> Result<(), String> {
+        let a = self.arg(0)?;
+        let b = self.arg(1)?;
+        let c = self.arg(2)?;
         debug!("  or a {} b {} c {}", a, b, c);
-        let rb = self.resolve(b);
-        let rc = self.resolve(c);
+        let rb = self.resolve(b)?;
+        let rc = self.resolve(c)?;
         debug!("  or rb {}, rc {}", rb, rc);
 
         let val = rb | rc;
         debug!("  or reg[{}] = {}", a, val);
-        self.write(a, val);
+        self.write(a, val)?;
 
         self.ip += 3;
+        Ok(())
     }
 
-    fn exec_not(&mut self) {
-        let a = self.memory[self.ip];
-        let b = self.memory[self.ip + 1];
+    fn exec_not(&mut self) -> Result<(), String> {
+        let a = self.arg(0)?;
+        let b = self.arg(1)?;
         debug!("  not a {} b {}", a, b);
-        let rb = self.resolve(b);
+        let rb = self.resolve(b)?;
         debug!(" not rb {}", rb);
 
-        let mut val = !rb;
-        if val > 32768 {
-            val -= 32768;
-        }
+        let val = !rb & 0x7fff;
         debug!("  not reg[{}] = {}", a, val);
-        self.write(a, val);
+        self.write(a, val)?;
 
         self.ip += 2;
+        Ok(())
     }
 
-    fn exec_rmem(&mut self) {
-        let a = self.memory[self.ip];
-        let mut b = self.memory[self.ip + 1];
+    fn exec_rmem(&mut self) -> Result<(), String> {
+        let a = self.arg(0)?;
+        let mut b = self.arg(1)?;
         debug!("  rmem a {}, b {}", a, b);
 
         if !is_memory(b) {
-            let b2 = self.resolve(b);
+            let b2 = self.resolve(b)?;
             debug!("  rmem resolve b {} = {}", b, b2);
             b = b2;
         }
-        let b = self.read(b);
+        let b = self.read(b)?;
 
         if is_memory(a) {
-            error!("  rmem only writes to registers, a is memory, {}", b);
-            self.exec_halt();
+            return Err(format!("rmem only writes to registers, a is memory, {}", a));
         }
         debug!("  rmem final a {}, b {}", a, b);
-        self.write(a, b);
+        self.write(a, b)?;
         self.ip += 2;
+        Ok(())
     }
 
-    fn exec_wmem(&mut self) {
-        let a = self.memory[self.ip];
-        let b = self.memory[self.ip + 1];
-        let ra = self.resolve(a);
-        let rb = self.resolve(b);
+    fn exec_wmem(&mut self) -> Result<(), String> {
+        let a = self.arg(0)?;
+        let b = self.arg(1)?;
+        let ra = self.resolve(a)?;
+        let rb = self.resolve(b)?;
         if !is_memory(ra) {
-            error!("  rmem only writes to memory, a is not, {}", b);
-            self.exec_halt();
+            return Err(format!("wmem only writes to memory, a is not, {}", ra));
         }
         debug!("  wmem a {}, b {}, rb {}", a, b, rb);
-        self.write(ra, rb);
+        self.write(ra, rb)?;
         self.ip += 2;
+        Ok(())
     }
 
-    fn exec_call(&mut self) {
-        let mut a = self.memory[self.ip];
+    fn exec_call(&mut self) -> Result<(), String> {
+        let mut a = self.arg(0)?;
         debug!("  call a {}", a);
         while !is_memory(a) {
-            let a2 = self.resolve(a);
+            let a2 = self.resolve(a)?;
             debug!("  call a {} => {}", a, a2);
             a = a2;
         }
         let next_inst = self.ip + 1;
         if a == 0 {
-            error!("  jumping to address 0");
-            self.exec_halt();
+            return Err("call to address 0".to_string());
         }
         debug!(" call push ip {}", next_inst);
         debug!(" call jumping to {}", a);
         self.stack.push(next_inst as u16);
+        self.generation += 1;
         self.ip = a as usize;
+        Ok(())
     }
 
-    fn exec_ret(&mut self) {
+    fn exec_ret(&mut self) -> Result<(), String> {
         if self.stack.is_empty() {
-            error!(" ret empty stack");
+            debug!(" ret empty stack");
             self.exec_halt();
+            return Ok(());
         }
         let val = self.stack.pop().unwrap();
+        self.generation += 1;
         self.ip = val as usize;
+        Ok(())
     }
 
-    fn exec_out(&mut self) {
-        let a = self.resolve(self.memory[self.ip]);
-        print!("{}", a as u8 as char);
+    fn exec_out(&mut self) -> Result<(), String> {
+        let a = self.resolve(self.arg(0)?)?;
+        if let Err(what) = write!(self.output, "{}", a as u8 as char) {
+            return Err(format!("failed to write output: {}", what));
+        }
         self.ip += 1;
+        Ok(())
     }
 
-    fn exec_in(&mut self) {
-        let a = self.memory[self.ip];
-        info!("  in a {}", a);
-
-        if self.input_buffer.is_empty() {
-            let mut line = String::new();
-            let numbytes = io::stdin().read_line(&mut line).unwrap();
-            info!("  in line '{}' numbytes {}", line, numbytes);
-            for chr in line.chars() {
-                self.input_buffer.push_back(chr as u16);
+    fn exec_in(&mut self) -> Result<(), String> {
+        while self.input_buffer.is_empty() {
+            match self.input.next_line() {
+                Some(line) if MetaCommands::is_meta(&line) => {
+                    // run with ip on the in instruction so a save resumes
+                    // here, anything that moved ip abandons this input
+                    let at = self.ip - 1;
+                    self.ip = at;
+                    let meta = std::mem::take(&mut self.meta);
+                    let reply = meta.run(self, line.trim_end());
+                    self.meta = meta;
+                    if let Err(what) = writeln!(self.output, "{}", reply) {
+                        return Err(format!("failed to write output: {}", what));
+                    }
+                    if self.ip != at || self.halted || self.current_op() != OpCodes::in_ {
+                        return Ok(());
+                    }
+                    self.ip = at + 1;
+                }
+                Some(line) => {
+                    info!("  in line '{}'", line);
+                    for chr in line.chars() {
+                        self.input_buffer.push_back(chr as u16);
+                    }
+                }
+                None => {
+                    info!("  end of input");
+                    self.ip -= 1;
+                    self.input_closed = true;
+                    self.exec_halt();
+                    return Ok(());
+                }
             }
         }
+        let a = self.arg(0)?;
+        info!("  in a {}", a);
         let val = self.input_buffer.pop_front().unwrap();
+        self.input_read += 1;
         debug!("  writing {}/{} at {}", val, val as u8 as char, a);
-        self.write(a, val);
+        self.write(a, val)?;
         self.ip += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(data: Vec<u16>, limits: &Limits) -> Outcome {
+        let mut program = Program::new();
+        program.data = data;
+        let mut host = Host::from(program);
+        host.set_output(Box::new(io::sink()));
+        host.run(limits)
+    }
+
+    #[test]
+    fn run_outcomes() {
+        let limits = Limits {
+            max_instructions: Some(100),
+            ..Limits::default()
+        };
+        // out 'A', halt
+        assert_eq!(run(vec![19, 65, 0], &limits), Outcome::Halted);
+        assert_eq!(
+            run(vec![21, 30], &limits),
+            Outcome::Fault("unknown instruction 30".to_string())
+        );
+        // pop with nothing pushed
+        assert!(matches!(run(vec![3, 32768], &limits), Outcome::Fault(_)));
+        // mod reg0 1 reg1 with reg1 still 0
+        assert_eq!(
+            run(vec![11, 32768, 1, 32769], &limits),
+            Outcome::Fault("mod by zero".to_string())
+        );
+        // add with its last operand past the end
+        assert!(matches!(run(vec![9, 32768, 1], &limits), Outcome::Fault(_)));
+        // jmp to a value that is no address or register
+        assert_eq!(
+            run(vec![6, 40000], &limits),
+            Outcome::Fault("invalid value 40000".to_string())
+        );
+        // jmp 0
+        assert_eq!(run(vec![6, 0], &limits), Outcome::InstructionLimit);
+
+        let limits = Limits {
+            detect_loops: true,
+            ..limits
+        };
+        // 0: nop, 1: jmp 0
+        assert_eq!(
+            run(vec![21, 6, 0], &limits),
+            Outcome::LoopDetected(vec![0, 1])
+        );
+    }
+
+    #[test]
+    fn branches_to_the_next_instruction() {
+        // 0: jt 1 3, 3: jf 1 6, 6: halt, both jump where they'd fall through
+        let mut program = Program::new();
+        program.data = vec![7, 1, 3, 8, 1, 6, 0];
+        let mut host = Host::from(program);
+        host.set_output(Box::new(io::sink()));
+        host.set_coverage(Some(Coverage::default()));
+        assert_eq!(host.run(&Limits::default()), Outcome::Halted);
+        let text = host.coverage.as_ref().unwrap().serialize();
+        assert!(text.contains("branch 0 1 0\n"));
+        assert!(text.contains("branch 3 0 1\n"));
+    }
+
+    #[test]
+    fn restore_clears_fault() {
+        let mut program = Program::new();
+        program.data = vec![21, 30];
+        let mut host = Host::from(program);
+        host.set_output(Box::new(io::sink()));
+        assert!(matches!(host.run(&Limits::default()), Outcome::Fault(_)));
+        // a single halt
+        let snapshot = "ip 0\ncount 0\nregisters 0 0 0 0 0 0 0 0\nstack\nmemory 0\n";
+        host.restore(snapshot).unwrap();
+        assert_eq!(host.run(&Limits::default()), Outcome::Halted);
+    }
+
+    #[test]
+    fn fast_path_sees_code_changes() {
+        // 0: add reg0 reg0 1, 4: wmem 0 10 (turns the add into mult),
+        // 7: jt reg1 15, 10: set reg1 1, 13: jmp 0, 15: halt
+        let data = vec![
+            9, 32768, 32768, 1, 16, 0, 10, 7, 32769, 15, 1, 32769, 1, 6, 0, 0,
+        ];
+        for fast in [false, true].iter() {
+            let mut program = Program::new();
+            program.data = data.clone();
+            let mut host = Host::from(program);
+            host.fast = *fast;
+            assert_eq!(host.run(&Limits::default()), Outcome::Halted);
+            assert_eq!(host.register(0), 1);
+            assert_eq!(host.count, 9);
+        }
     }
 }
@@ -0,0 +1,155 @@
+use std::collections::vec_deque;
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufWriter, Write};
+use std::path;
+
+use log::{error, info};
+
+// Input scripts have one line of game input per line. Lines starting with
+// '#' and empty lines are skipped, so scripts can be commented:
+//   # get the tablet
+//   take tablet
+//   use tablet
+// Replay files start with REPLAY_HEADER and are taken verbatim after it,
+// blank lines and '#' included, so they play back what was typed.
+
+const REPLAY_HEADER: &str = "# replay of a runner session";
+
+/// Where the lines read by the `in` instruction come from
+pub struct Input {
+    script: vec_deque::VecDeque<String>,
+    replay: Option<Box<dyn Write + Send>>,
+    /// read stdin once the script is used up
+    pub stdin: bool,
+    /// print script lines as if they were typed
+    pub echo: bool,
+}
+
+pub fn parse_script(text: &str) -> vec_deque::VecDeque<String> {
+    let mut lines = text.lines();
+    if lines.next() == Some(REPLAY_HEADER) {
+        return lines.map(String::from).collect();
+    }
+    text.lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+impl Input {
+    pub fn new() -> Input {
+        Input {
+            script: vec_deque::VecDeque::new(),
+            replay: None,
+            stdin: true,
+            echo: true,
+        }
+    }
+
+    /// Queues the lines of a script, they are used before reading stdin
+    pub fn load_script(&mut self, path: &path::Path) -> io::Result<()> {
+        let text = fs::read_to_string(path)?;
+        let lines = parse_script(&text);
+        info!(
+            "loaded {} lines of input from {}",
+            lines.len(),
+            path.display()
+        );
+        self.script.extend(lines);
+        Ok(())
+    }
+
+    /// Queues the lines of a file verbatim, comments included
+    pub fn load_input(&mut self, path: &path::Path) -> io::Result<()> {
+        let text = fs::read_to_string(path)?;
+        self.script.extend(text.lines().map(String::from));
+        Ok(())
+    }
+
+    /// Records every line handed to the program, the file can be used as a
+    /// script to replay the session
+    pub fn record_replay(&mut self, path: &path::Path) -> io::Result<()> {
+        let mut out = BufWriter::new(fs::File::create(path)?);
+        writeln!(out, "{}", REPLAY_HEADER)?;
+        self.replay = Some(Box::new(out));
+        Ok(())
+    }
+
+    /// The next line of input including the newline, None once stdin is
+    /// closed and the script is used up
+    pub fn next_line(&mut self) -> Option<String> {
+        let line = match self.script.pop_front() {
+            Some(line) => {
+                // echo so the transcript reads like the game was typed into
+                if self.echo {
+                    println!("{}", line);
+                }
+                line
+            }
+            None if !self.stdin => return None,
+            None => {
+                let mut line = String::new();
+                match io::stdin().lock().read_line(&mut line) {
+                    Ok(0) => return None,
+                    Ok(_) => line.trim_end_matches(&['\r', '\n'][..]).to_string(),
+                    Err(what) => {
+                        error!("failed to read input: {}", what);
+                        return None;
+                    }
+                }
+            }
+        };
+        self.record(&line);
+        Some(line + "\n")
+    }
+
+    fn record(&mut self, line: &str) {
+        if let Some(out) = self.replay.as_mut() {
+            let result = writeln!(out, "{}", line).and_then(|_| out.flush());
+            if let Err(what) = result {
+                error!("failed to write replay, recording stopped: {}", what);
+                self.replay = None;
+            }
+        }
+    }
+}
+
+impl Default for Input {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script_skips_comments() {
+        let script = parse_script("# start\ntake tablet\n\n  # indented\nuse tablet  \n");
+        assert_eq!(script, vec!["take tablet", "use tablet"]);
+    }
+
+    #[test]
+    fn replay_is_verbatim() {
+        let path = std::env::temp_dir().join(format!("replay-{}.txt", std::process::id()));
+        let mut input = Input::new();
+        input.echo = false;
+        input.stdin = false;
+        input.script.extend(vec![
+            "".to_string(),
+            "# not a comment".to_string(),
+            "  look ".to_string(),
+        ]);
+        input.record_replay(&path).unwrap();
+        while input.next_line().is_some() {}
+        input.replay = None;
+
+        let mut replay = Input::new();
+        replay.load_script(&path).unwrap();
+        assert_eq!(replay.script, vec!["", "# not a comment", "  look "]);
+        fs::remove_file(&path).unwrap();
+    }
+}
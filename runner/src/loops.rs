@@ -0,0 +1,153 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{vec_deque, HashMap};
+use std::hash::{Hash, Hasher};
+
+// Two ways of noticing the vm will never stop:
+// - ip comes back to an address without anything having been written since
+//   the last visit, the state is identical so it will keep going around
+// - the hash of the whole vm state, taken every SAMPLE_INTERVAL instructions,
+//   repeats. That catches loops that write but cycle through the same states.
+//   How much input was read is part of the state, a program that comes back
+//   to the same state after reading a line still makes progress.
+
+const SAMPLE_INTERVAL: u64 = 1024;
+const MAX_SAMPLES: usize = 1 << 20;
+const RECENT: usize = 1 << 16;
+
+pub struct LoopDetector {
+    steps: u64,
+    generation: u64,
+    // ips since the last write and where in that list each one is
+    since_write: Vec<usize>,
+    positions: HashMap<usize, usize>,
+    samples: HashMap<u64, u64>,
+    recent: vec_deque::VecDeque<usize>,
+}
+
+fn unique(addresses: &[usize]) -> Vec<usize> {
+    let mut retr = addresses.to_vec();
+    retr.sort_unstable();
+    retr.dedup();
+    retr
+}
+
+impl LoopDetector {
+    pub fn new() -> LoopDetector {
+        LoopDetector {
+            steps: 0,
+            generation: 0,
+            since_write: Vec::new(),
+            positions: HashMap::new(),
+            samples: HashMap::new(),
+            recent: vec_deque::VecDeque::new(),
+        }
+    }
+
+    /// Called before each instruction. `generation` changes whenever the vm
+    /// writes to memory, registers or the stack, `input` is the number of
+    /// characters read so far. Returns the addresses of the loop once one is
+    /// found.
+    pub fn check(
+        &mut self,
+        ip: usize,
+        generation: u64,
+        input: u64,
+        registers: &[u16],
+        stack: &[u16],
+        memory: &[u16],
+    ) -> Option<Vec<usize>> {
+        self.steps += 1;
+        if self.recent.len() == RECENT {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(ip);
+
+        if generation != self.generation {
+            self.generation = generation;
+            self.since_write.clear();
+            self.positions.clear();
+        }
+        if let Some(pos) = self.positions.get(&ip) {
+            return Some(unique(&self.since_write[*pos..]));
+        }
+        self.positions.insert(ip, self.since_write.len());
+        self.since_write.push(ip);
+
+        if self.steps % SAMPLE_INTERVAL != 0 {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        (ip, input, registers, stack, memory).hash(&mut hasher);
+        let hash = hasher.finish();
+        match self.samples.insert(hash, self.steps) {
+            Some(previous) => {
+                let length = usize::min((self.steps - previous) as usize, self.recent.len());
+                let (front, back) = self.recent.as_slices();
+                let all: Vec<usize> = front.iter().chain(back.iter()).copied().collect();
+                Some(unique(&all[all.len() - length..]))
+            }
+            None => {
+                if self.samples.len() > MAX_SAMPLES {
+                    self.samples.clear();
+                }
+                None
+            }
+        }
+    }
+}
+
+impl Default for LoopDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loop_without_writes() {
+        let mut detector = LoopDetector::new();
+        let registers = [0; 8];
+        let mut found = None;
+        for ip in [0, 2, 5, 7, 2].iter() {
+            found = detector.check(*ip, 0, 0, &registers, &[], &[]);
+        }
+        assert_eq!(found, Some(vec![2, 5, 7]));
+    }
+
+    #[test]
+    fn repeating_state() {
+        // reg0 toggles, so there is a write every time round
+        let mut detector = LoopDetector::new();
+        let mut registers = [0; 8];
+        let mut generation = 0;
+        for step in 0..4 * SAMPLE_INTERVAL {
+            let ip = (step % 2) as usize * 3;
+            if ip == 0 {
+                registers[0] ^= 1;
+                generation += 1;
+            }
+            if let Some(addresses) = detector.check(ip, generation, 0, &registers, &[], &[]) {
+                assert_eq!(addresses, vec![0, 3]);
+                return;
+            }
+        }
+        panic!("loop not detected");
+    }
+
+    #[test]
+    fn input_is_progress() {
+        // the same state comes round every sample, but with a character read
+        // in between
+        let mut detector = LoopDetector::new();
+        let registers = [0; 8];
+        for step in 0..4 * SAMPLE_INTERVAL {
+            let ip = (step % 2) as usize * 3;
+            let input = step / SAMPLE_INTERVAL;
+            let found = detector.check(ip, step / 2, input, &registers, &[], &[]);
+            assert_eq!(found, None);
+        }
+    }
+}
@@ -1,31 +1,328 @@
 use std::env;
-use std::io::Write;
+use std::fs;
+use std::io::{BufWriter, Write};
 use std::path;
+use std::process;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time;
 
 #[macro_use]
 extern crate log;
 
 use env_logger::{Builder, Target};
+use signal_hook::consts::SIGINT;
+use signal_hook::flag;
 
+mod coverage;
+mod dap;
 mod debugserver;
+mod gdbstub;
+mod hooks;
 mod host;
+mod input;
+mod loops;
+mod meta;
+mod profiler;
+mod tracer;
 
 use code::program;
+use coverage::Coverage;
+use messages::{Endpoint, DEFAULT_PORT};
+use profiler::Profiler;
+use tracer::{TraceFilter, TraceFormat, TraceMode, Tracer};
+
+enum Mode {
+    Debugserver,
+    Gdb,
+    Dap,
+    CoverageReport(String),
+    Batch,
+    Benchmark,
+}
 
 struct Config {
     filename: path::PathBuf,
+    mode: Mode,
+    host: String,
+    port: Option<u16>,
+    unix: Option<path::PathBuf>,
+    trace: Option<path::PathBuf>,
+    trace_format: TraceFormat,
+    trace_mode: TraceMode,
+    trace_filter: TraceFilter,
+    profile: Option<path::PathBuf>,
+    profile_folded: Option<path::PathBuf>,
+    profile_top: usize,
+    coverage: Vec<path::PathBuf>,
+    scripts: Vec<path::PathBuf>,
+    replay: Option<path::PathBuf>,
+    input: Option<path::PathBuf>,
+    output: Option<path::PathBuf>,
+    limits: host::Limits,
+    teleporter: Option<usize>,
+}
+
+// Exit status of every mode: 5 the program doesn't load, 6 invalid
+// arguments. Batch runs add their own, see batch.
+const LOAD_ERROR: i32 = 5;
+const USAGE_ERROR: i32 = 6;
+
+const USAGE: &str = "usage: runner BINARY [--gdb|--dap|--run|--benchmark|--coverage-report OUT] \
+    [--host HOST] [--port PORT] [--unix PATH] \
+    [--trace FILE] [--trace-format text|binary] [--trace-calls] [--trace-range FROM:TO] \
+    [--trace-function ADDR] [--profile FILE] [--profile-folded FILE] [--profile-top N] \
+    [--coverage FILE]... [--script FILE]... [--replay FILE] [--input FILE] [--output FILE] \
+    [--max-instructions N] [--timeout SECONDS] [--detect-loops] [--hook-teleporter ADDR]";
+
+fn value<'a>(arg: &str, args: &mut impl Iterator<Item = &'a String>) -> Result<&'a String, String> {
+    args.next().ok_or(format!("{} needs a value", arg))
+}
+
+fn number(arg: &str, text: &str) -> Result<usize, String> {
+    text.parse::<usize>()
+        .map_err(|what| format!("invalid value '{}' for {}: {}", text, arg, what))
 }
 
 impl Config {
-    fn new(args: &[String]) -> Config {
-        if args.len() < 2 {
-            panic!("No binary supplied");
+    fn new(args: &[String]) -> Result<Config, String> {
+        if args.len() < 2 || args[1].starts_with("--") {
+            return Err("No binary supplied".to_string());
         }
         let filename = args[1].clone();
-        Config {
+        let mut config = Config {
             filename: path::PathBuf::from(filename),
+            mode: Mode::Debugserver,
+            host: String::from("0.0.0.0"),
+            port: None,
+            unix: None,
+            trace: None,
+            trace_format: TraceFormat::Text,
+            trace_mode: TraceMode::Instructions,
+            trace_filter: TraceFilter::default(),
+            profile: None,
+            profile_folded: None,
+            profile_top: 20,
+            coverage: Vec::new(),
+            scripts: Vec::new(),
+            replay: None,
+            input: None,
+            output: None,
+            limits: host::Limits::default(),
+            teleporter: None,
+        };
+        let mut rest = args[2..].iter();
+        while let Some(arg) = rest.next() {
+            match arg.as_ref() {
+                "--gdb" => config.mode = Mode::Gdb,
+                "--dap" => config.mode = Mode::Dap,
+                "--host" => config.host = value(arg, &mut rest)?.clone(),
+                "--port" => match value(arg, &mut rest)?.parse::<u16>() {
+                    Ok(port) => config.port = Some(port),
+                    Err(what) => return Err(format!("invalid port: {}", what)),
+                },
+                "--unix" => config.unix = Some(path::PathBuf::from(value(arg, &mut rest)?)),
+                "--trace" => config.trace = Some(path::PathBuf::from(value(arg, &mut rest)?)),
+                "--trace-format" => {
+                    config.trace_format = match value(arg, &mut rest)?.as_ref() {
+                        "text" => TraceFormat::Text,
+                        "binary" => TraceFormat::Binary,
+                        other => return Err(format!("unknown trace format {}", other)),
+                    }
+                }
+                "--trace-calls" => config.trace_mode = TraceMode::Calls,
+                "--trace-range" => {
+                    let range = value(arg, &mut rest)?;
+                    config.trace_filter.range = match range.split_once(':') {
+                        Some((from, to)) => Some((number(arg, from)?, number(arg, to)?)),
+                        None => return Err("--trace-range expects FROM:TO".to_string()),
+                    }
+                }
+                "--trace-function" => {
+                    config.trace_filter.function = Some(number(arg, value(arg, &mut rest)?)?)
+                }
+                "--profile" => config.profile = Some(path::PathBuf::from(value(arg, &mut rest)?)),
+                "--profile-folded" => {
+                    config.profile_folded = Some(path::PathBuf::from(value(arg, &mut rest)?))
+                }
+                "--profile-top" => config.profile_top = number(arg, value(arg, &mut rest)?)?,
+                "--coverage" => config
+                    .coverage
+                    .push(path::PathBuf::from(value(arg, &mut rest)?)),
+                "--script" => config
+                    .scripts
+                    .push(path::PathBuf::from(value(arg, &mut rest)?)),
+                "--replay" => config.replay = Some(path::PathBuf::from(value(arg, &mut rest)?)),
+                "--run" => config.mode = Mode::Batch,
+                "--benchmark" => config.mode = Mode::Benchmark,
+                "--input" => config.input = Some(path::PathBuf::from(value(arg, &mut rest)?)),
+                "--output" => config.output = Some(path::PathBuf::from(value(arg, &mut rest)?)),
+                "--max-instructions" => {
+                    config.limits.max_instructions =
+                        Some(number(arg, value(arg, &mut rest)?)? as u64)
+                }
+                "--timeout" => {
+                    let seconds = number(arg, value(arg, &mut rest)?)? as u64;
+                    config.limits.timeout = Some(time::Duration::from_secs(seconds))
+                }
+                "--detect-loops" => config.limits.detect_loops = true,
+                "--hook-teleporter" => {
+                    config.teleporter = Some(number(arg, value(arg, &mut rest)?)?)
+                }
+                "--coverage-report" => {
+                    config.mode = Mode::CoverageReport(value(arg, &mut rest)?.clone())
+                }
+                _ => return Err(format!("unknown argument {}", arg)),
+            }
+        }
+        // call records only have a text format
+        if config.trace_mode == TraceMode::Calls && config.trace_format == TraceFormat::Binary {
+            return Err("--trace-calls can't be combined with --trace-format binary".to_string());
+        }
+        if config.unix.is_some() && matches!(config.mode, Mode::Gdb | Mode::Dap) {
+            return Err("--unix is only supported by the debugserver".to_string());
+        }
+        Ok(config)
+    }
+
+    fn tcp_address(&self, default_port: u16) -> String {
+        format!("{}:{}", self.host, self.port.unwrap_or(default_port))
+    }
+
+    fn endpoint(&self) -> Endpoint {
+        match &self.unix {
+            Some(path) => Endpoint::Unix(path.clone()),
+            None => Endpoint::tcp(&self.host, self.port.unwrap_or(DEFAULT_PORT)),
+        }
+    }
+}
+
+/// Merges all coverage files into an annotated listing of the program
+fn coverage_report(config: &Config, program: &program::Program, out: &str) {
+    let mut coverage = Coverage::default();
+    for path in &config.coverage {
+        match Coverage::load(path) {
+            Ok(other) => coverage.merge(&other),
+            Err(what) => panic!("{}", what),
+        }
+    }
+    let listing = coverage.annotate(&program.data);
+    match out {
+        "-" => print!("{}", listing),
+        _ => {
+            if let Err(what) = fs::write(out, listing) {
+                panic!("Failed to write {} : {}", out, what);
+            }
+        }
+    }
+}
+
+/// Runs the program without a debugger. The exit status tells how it ended:
+/// 0 halted, 1 fault, 2 instruction limit or timeout, 3 input ran out,
+/// 4 endless loop, 130 interrupted, on top of the load and usage errors
+/// every mode has
+fn batch(config: &Config, mut host: host::Host) -> ! {
+    if let Some(path) = &config.input {
+        if let Err(what) = host.input.load_input(path) {
+            panic!("Failed to read input {} : {}", path.display(), what);
+        }
+        host.input.stdin = false;
+    }
+    host.input.echo = false;
+    if let Some(path) = &config.output {
+        match fs::File::create(path) {
+            Ok(file) => host.set_output(Box::new(BufWriter::new(file))),
+            Err(what) => panic!("Failed to create output {} : {}", path.display(), what),
+        }
+    }
+
+    // the first Ctrl-C stops the run so profiles and coverage get written,
+    // a second one exits right away, for example while waiting for input
+    let interrupt = Arc::new(AtomicBool::new(false));
+    for result in [
+        flag::register_conditional_shutdown(SIGINT, 130, interrupt.clone()),
+        flag::register(SIGINT, interrupt.clone()),
+    ] {
+        if let Err(what) = result {
+            warn!("Ctrl-C will not save profiles: {}", what);
+        }
+    }
+    let limits = host::Limits {
+        interrupt: Some(interrupt),
+        ..config.limits.clone()
+    };
+
+    let outcome = host.run(&limits);
+    let code = match &outcome {
+        host::Outcome::Halted => 0,
+        host::Outcome::Fault(what) => {
+            eprintln!("fault: {}", what);
+            1
+        }
+        host::Outcome::InstructionLimit | host::Outcome::Timeout => {
+            eprintln!("stopped at {}: {:?}", host.ip(), outcome);
+            2
+        }
+        host::Outcome::EndOfInput => 3,
+        host::Outcome::LoopDetected(addresses) => {
+            eprintln!("endless loop at {:?}", addresses);
+            4
+        }
+        host::Outcome::Interrupted => {
+            eprintln!("interrupted at {}", host.ip());
+            130
+        }
+    };
+    process::exit(code);
+}
+
+const BENCHMARK_RUNS: usize = 10;
+
+fn benchmark_run(config: &Config, program: &program::Program, fast: bool) -> host::Host {
+    let mut host = host::Host::from(program.clone());
+    host.fast = fast;
+    host.input.stdin = false;
+    host.input.echo = false;
+    if let Some(path) = &config.input {
+        if let Err(what) = host.input.load_input(path) {
+            panic!("Failed to read input {} : {}", path.display(), what);
         }
     }
+    host.set_output(Box::new(std::io::sink()));
+    host
+}
+
+/// Runs the program with the reference and the fast interpreter, without
+/// output, and reports instructions per second
+fn benchmark(config: &Config, program: &program::Program) {
+    let mut states = Vec::new();
+    for (name, fast) in [("reference", false), ("fast", true)].iter() {
+        let mut count = 0;
+        let mut seconds = 0.0;
+        let mut outcome = host::Outcome::Halted;
+        let mut state = None;
+        for _ in 0..BENCHMARK_RUNS {
+            let mut host = benchmark_run(config, program, *fast);
+            let start = time::Instant::now();
+            outcome = host.run(&config.limits);
+            seconds += start.elapsed().as_secs_f64();
+            let end = host.create_state();
+            count += end.count;
+            state = Some((end.registers, end.ip, end.count));
+        }
+        println!(
+            "{:>10} {:>12} instructions in {:>8.3}s {:>14.0} instructions/s ({:?})",
+            name,
+            count,
+            seconds,
+            count as f64 / seconds,
+            outcome
+        );
+        states.push(state);
+    }
+    if states[0] != states[1] {
+        println!("interpreters disagree: {:?} vs {:?}", states[0], states[1]);
+    }
 }
 
 fn main() {
@@ -36,12 +333,83 @@ fn main() {
         .init();
 
     let args: Vec<String> = env::args().collect();
-    let config = Config::new(&args);
+    let config = match Config::new(&args) {
+        Ok(config) => config,
+        Err(what) => {
+            eprintln!("{}\n{}", what, USAGE);
+            process::exit(USAGE_ERROR);
+        }
+    };
     info!("Running file {}", config.filename.display());
 
-    let program = program::Program::parse_file(&config.filename);
+    let program = match program::Program::parse_file(&config.filename) {
+        Ok(program) => program,
+        Err(what) => {
+            eprintln!("{}: {}", config.filename.display(), what);
+            process::exit(LOAD_ERROR);
+        }
+    };
+    for warning in &program.warnings {
+        warn!("{}", warning);
+    }
+    if let Mode::CoverageReport(out) = &config.mode {
+        coverage_report(&config, &program, out);
+        return;
+    }
+    if let Mode::Benchmark = config.mode {
+        benchmark(&config, &program);
+        return;
+    }
 
-    debugserver::Debugserver::start(program);
-    //let mut host = host::Host::new();
-    //host.run(program);
+    let mut host = host::Host::from(program);
+    if let Some(path) = &config.trace {
+        match Tracer::create(
+            path,
+            config.trace_format,
+            config.trace_mode,
+            config.trace_filter.clone(),
+        ) {
+            Ok(tracer) => host.set_tracer(Some(tracer)),
+            Err(what) => panic!("Failed to create trace {} : {}", path.display(), what),
+        }
+    }
+
+    if config.profile.is_some() || config.profile_folded.is_some() {
+        host.set_profiler(Some(Profiler::new(
+            config.profile.clone(),
+            config.profile_folded.clone(),
+            config.profile_top,
+        )));
+    }
+
+    for path in &config.scripts {
+        if let Err(what) = host.input.load_script(path) {
+            panic!("Failed to read script {} : {}", path.display(), what);
+        }
+    }
+    if let Some(path) = &config.replay {
+        if let Err(what) = host.input.record_replay(path) {
+            panic!("Failed to create replay {} : {}", path.display(), what);
+        }
+    }
+
+    if let Some(address) = config.teleporter {
+        host.hooks.add(address, true, Box::new(hooks::teleporter));
+    }
+
+    // the first coverage file collects this run, on top of earlier runs
+    if let Some(path) = config.coverage.first() {
+        match Coverage::open(path) {
+            Ok(coverage) => host.set_coverage(Some(coverage)),
+            Err(what) => panic!("{}", what),
+        }
+    }
+
+    match &config.mode {
+        Mode::Debugserver => debugserver::Debugserver::start(host, &config.endpoint()),
+        Mode::Gdb => gdbstub::GdbStub::start(host, &config.tcp_address(1234)),
+        Mode::Dap => dap::DapServer::start(host, &config.tcp_address(4711)),
+        Mode::Batch => batch(&config, host),
+        Mode::CoverageReport(_) | Mode::Benchmark => {}
+    }
 }
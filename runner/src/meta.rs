@@ -0,0 +1,246 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path;
+
+use crate::host::Host;
+use crate::tracer::{TraceFilter, TraceFormat, TraceMode, Tracer};
+
+// Lines of game input starting with PREFIX are handled by the runner and
+// never reach the program, e.g. "!save maze" or "!setreg 7 1234".
+
+pub const PREFIX: char = '!';
+
+/// Handles the arguments of a meta command, the Ok text is shown to the player
+pub type MetaHandler = Box<dyn Fn(&mut Host, &[&str]) -> Result<String, String> + Send>;
+
+struct Entry {
+    usage: String,
+    handler: MetaHandler,
+}
+
+#[derive(Default)]
+pub struct MetaCommands {
+    commands: BTreeMap<String, Entry>,
+}
+
+fn snapshot_path(name: &str) -> path::PathBuf {
+    path::PathBuf::from(format!("{}.state", name))
+}
+
+fn parse_number(text: &str) -> Result<u16, String> {
+    text.parse::<u16>()
+        .map_err(|e| format!("invalid number '{}': {}", text, e))
+}
+
+impl MetaCommands {
+    /// The commands every runner understands
+    pub fn builtin() -> MetaCommands {
+        let mut meta = MetaCommands::default();
+        meta.register("save", "save NAME", Box::new(save));
+        meta.register("load", "load NAME", Box::new(load));
+        meta.register("setreg", "setreg REG VALUE", Box::new(setreg));
+        meta.register("dump", "dump [FILE]", Box::new(dump));
+        meta.register("trace", "trace on [FILE] | off", Box::new(trace));
+        meta
+    }
+
+    /// Adds or replaces a command
+    pub fn register(&mut self, name: &str, usage: &str, handler: MetaHandler) {
+        self.commands.insert(
+            name.to_string(),
+            Entry {
+                usage: usage.to_string(),
+                handler,
+            },
+        );
+    }
+
+    pub fn is_meta(line: &str) -> bool {
+        line.starts_with(PREFIX)
+    }
+
+    /// Runs a meta command line and returns what to show the player
+    pub fn run(&self, host: &mut Host, line: &str) -> String {
+        let words: Vec<&str> = line.trim_start_matches(PREFIX).split_whitespace().collect();
+        let name = match words.first() {
+            Some(name) => *name,
+            None => return self.help(),
+        };
+        match self.commands.get(name) {
+            Some(entry) => match (entry.handler)(host, &words[1..]) {
+                Ok(text) => text,
+                Err(what) => format!(
+                    "{}{}: {}\nusage: {}{}",
+                    PREFIX, name, what, PREFIX, entry.usage
+                ),
+            },
+            None if name == "help" => self.help(),
+            None => format!("unknown command {}{}\n{}", PREFIX, name, self.help()),
+        }
+    }
+
+    fn help(&self) -> String {
+        let usages: Vec<String> = self
+            .commands
+            .values()
+            .map(|e| format!("  {}{}", PREFIX, e.usage))
+            .collect();
+        format!("runner commands:\n{}", usages.join("\n"))
+    }
+}
+
+fn save(host: &mut Host, args: &[&str]) -> Result<String, String> {
+    let name = args.first().ok_or("missing name")?;
+    let path = snapshot_path(name);
+    fs::write(&path, host.snapshot())
+        .map_err(|e| format!("failed to write {} : {}", path.display(), e))?;
+    Ok(format!("saved {}", path.display()))
+}
+
+fn load(host: &mut Host, args: &[&str]) -> Result<String, String> {
+    let name = args.first().ok_or("missing name")?;
+    let path = snapshot_path(name);
+    let text = fs::read_to_string(&path)
+        .map_err(|e| format!("failed to read {} : {}", path.display(), e))?;
+    host.restore(&text)?;
+    Ok(format!("loaded {}, at {}", path.display(), host.ip()))
+}
+
+fn setreg(host: &mut Host, args: &[&str]) -> Result<String, String> {
+    if args.len() != 2 {
+        return Err("expected a register and a value".to_string());
+    }
+    let reg = parse_number(args[0].trim_start_matches("reg"))? as usize;
+    let value = parse_number(args[1])?;
+    if reg > 7 {
+        return Err(format!("invalid register {}", reg));
+    }
+    if value > 32767 {
+        return Err(format!("value {} out of range", value));
+    }
+    host.set_register(reg, value);
+    Ok(format!("reg{} = {}", reg, value))
+}
+
+fn dump(host: &mut Host, args: &[&str]) -> Result<String, String> {
+    let state = host.create_state();
+    let mut retr = format!(
+        "ip {} count {}\nregisters {:?}\nstack {:?}",
+        state.ip,
+        state.count,
+        state.registers,
+        host.stack()
+    );
+    if let Some(file) = args.first() {
+        let image: Vec<u8> = host
+            .create_memory_dump(0, host.memory_size())
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect();
+        fs::write(file, image).map_err(|e| format!("failed to write {} : {}", file, e))?;
+        retr += &format!("\nmemory written to {}", file);
+    }
+    Ok(retr)
+}
+
+fn trace(host: &mut Host, args: &[&str]) -> Result<String, String> {
+    match args.first() {
+        Some(&"on") => {
+            if host.tracing(true) {
+                return Ok("tracing on".to_string());
+            }
+            let file = args.get(1).unwrap_or(&"trace.txt");
+            let tracer = Tracer::create(
+                path::Path::new(file),
+                TraceFormat::Text,
+                TraceMode::Instructions,
+                TraceFilter::default(),
+            )
+            .map_err(|e| format!("failed to create {} : {}", file, e))?;
+            host.set_tracer(Some(tracer));
+            Ok(format!("tracing to {}", file))
+        }
+        Some(&"off") => {
+            host.tracing(false);
+            Ok("tracing off".to_string())
+        }
+        _ => Err("expected on or off".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use code::program::Program;
+
+    fn host() -> Host {
+        let mut program = Program::new();
+        // in reg0, halt
+        program.data = vec![20, 32768, 0];
+        Host::from(program)
+    }
+
+    #[test]
+    fn builtin_commands() {
+        let meta = MetaCommands::builtin();
+        let mut host = host();
+        assert_eq!(meta.run(&mut host, "!setreg 7 1234"), "reg7 = 1234");
+        assert_eq!(host.register(7), 1234);
+        assert!(meta
+            .run(&mut host, "!setreg 9 1")
+            .starts_with("!setreg: invalid register 9"));
+        assert!(meta
+            .run(&mut host, "!dump")
+            .contains("registers [0, 0, 0, 0, 0, 0, 0, 1234]"));
+        assert!(meta
+            .run(&mut host, "!bogus")
+            .starts_with("unknown command !bogus"));
+    }
+
+    #[test]
+    fn intercepted_from_input() {
+        let path = std::env::temp_dir().join("runner-meta-script");
+        fs::write(&path, "!setreg 7 5\nx\n").unwrap();
+        let mut host = host();
+        host.input.load_script(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        host.step();
+        assert_eq!(host.register(7), 5);
+        assert_eq!(host.register(0), 'x' as u16);
+        assert_eq!(host.ip(), 2);
+    }
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let mut other = host();
+        let mut host = host();
+        host.set_register(3, 42);
+        host.set_ip(2);
+        let snapshot = host.snapshot();
+        other.restore(&snapshot).unwrap();
+        assert_eq!(other.register(3), 42);
+        assert_eq!(other.ip(), 2);
+        assert_eq!(other.snapshot(), snapshot);
+        assert!(other.restore("ip 1").is_err());
+        // counts outgrow a word long before a session ends
+        let long = snapshot.replace("count 0", "count 5000000000");
+        other.restore(&long).unwrap();
+        assert_eq!(other.snapshot(), long);
+    }
+
+    #[test]
+    fn custom_handler() {
+        let mut meta = MetaCommands::default();
+        meta.register(
+            "warp",
+            "warp ADDRESS",
+            Box::new(|host, args| {
+                host.set_ip(parse_number(args[0])? as usize);
+                Ok("whoosh".to_string())
+            }),
+        );
+        let mut host = host();
+        assert_eq!(meta.run(&mut host, "!warp 2"), "whoosh");
+        assert_eq!(host.ip(), 2);
+    }
+}
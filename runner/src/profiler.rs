@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path;
+
+use log::{error, info};
+
+use code::opcodes::OpCodes;
+
+const MEMORY_WORDS: usize = 32768;
+const ENTRY: usize = 0;
+
+/// A node in the call tree, one per distinct call stack. Recursion goes
+/// back to the node the function already has on the stack, so the tree
+/// stays as deep as the number of distinct functions.
+struct Frame {
+    function: usize,
+    parent: usize,
+    count: u64,
+}
+
+/// Counts executed instructions per address, opcode and call stack.
+/// Functions are whatever gets called, the program entry counts as
+/// function 0.
+pub struct Profiler {
+    total: u64,
+    address_counts: Vec<u64>,
+    opcodes: Vec<u16>,
+    opcode_counts: HashMap<u16, u64>,
+    frames: Vec<Frame>,
+    children: HashMap<(usize, usize), usize>,
+    current: usize,
+    /// the frames calls came from, popped on ret
+    returns: Vec<usize>,
+    /// how often each function is on the call stack
+    depth: HashMap<usize, usize>,
+    /// total when a function went onto the call stack
+    entered: HashMap<usize, u64>,
+    /// instructions run while a function was on the call stack, up to its
+    /// last return
+    inclusive: HashMap<usize, u64>,
+    report_path: Option<path::PathBuf>,
+    folded_path: Option<path::PathBuf>,
+    top: usize,
+}
+
+fn function_name(address: usize) -> String {
+    match address {
+        ENTRY => "entry".to_string(),
+        _ => format!("fn_{}", address),
+    }
+}
+
+fn percent(count: u64, total: u64) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    count as f64 * 100.0 / total as f64
+}
+
+impl Profiler {
+    pub fn new(
+        report_path: Option<path::PathBuf>,
+        folded_path: Option<path::PathBuf>,
+        top: usize,
+    ) -> Profiler {
+        let mut depth = HashMap::new();
+        depth.insert(ENTRY, 1);
+        let mut entered = HashMap::new();
+        entered.insert(ENTRY, 0);
+        Profiler {
+            total: 0,
+            address_counts: vec![0; MEMORY_WORDS],
+            opcodes: vec![0; MEMORY_WORDS],
+            opcode_counts: HashMap::new(),
+            frames: vec![Frame {
+                function: ENTRY,
+                parent: 0,
+                count: 0,
+            }],
+            children: HashMap::new(),
+            current: 0,
+            returns: Vec::new(),
+            depth,
+            entered,
+            inclusive: HashMap::new(),
+            report_path,
+            folded_path,
+            top,
+        }
+    }
+
+    /// Records the instruction about to execute. `call_target` is the
+    /// resolved destination when the instruction is a call.
+    pub fn record(&mut self, ip: usize, opcode: u16, call_target: Option<usize>) {
+        self.total += 1;
+        if ip < MEMORY_WORDS {
+            self.address_counts[ip] += 1;
+            self.opcodes[ip] = opcode;
+        }
+        *self.opcode_counts.entry(opcode).or_insert(0) += 1;
+        self.frames[self.current].count += 1;
+
+        match OpCodes::parse(opcode) {
+            OpCodes::call => self.call(call_target.unwrap_or(0)),
+            // returning from the entry means the stack was used for jumps,
+            // stay at the root
+            OpCodes::ret => {
+                if let Some(frame) = self.returns.pop() {
+                    let function = self.frames[self.current].function;
+                    self.leave(function);
+                    self.current = frame;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn call(&mut self, target: usize) {
+        self.returns.push(self.current);
+        let depth = self.depth.entry(target).or_insert(0);
+        *depth += 1;
+        if *depth > 1 {
+            // recursion, back to the frame the function already has
+            let mut idx = self.current;
+            while self.frames[idx].function != target && idx != 0 {
+                idx = self.frames[idx].parent;
+            }
+            if self.frames[idx].function == target {
+                self.current = idx;
+                return;
+            }
+        } else {
+            self.entered.insert(target, self.total);
+        }
+        let next = self.frames.len();
+        let parent = self.current;
+        self.current = *self.children.entry((parent, target)).or_insert(next);
+        if self.current == next {
+            self.frames.push(Frame {
+                function: target,
+                parent,
+                count: 0,
+            });
+        }
+    }
+
+    fn leave(&mut self, function: usize) {
+        let depth = self.depth.entry(function).or_insert(1);
+        *depth -= 1;
+        if *depth == 0 {
+            let entered = self.entered.get(&function).copied().unwrap_or(0);
+            *self.inclusive.entry(function).or_insert(0) += self.total - entered;
+        }
+    }
+
+    fn stack(&self, frame: usize) -> Vec<usize> {
+        let mut stack = vec![self.frames[frame].function];
+        let mut idx = frame;
+        while idx != 0 {
+            idx = self.frames[idx].parent;
+            stack.push(self.frames[idx].function);
+        }
+        stack.reverse();
+        stack
+    }
+
+    /// (self, inclusive) instruction counts per function
+    fn function_counts(&self) -> HashMap<usize, (u64, u64)> {
+        let mut counts: HashMap<usize, (u64, u64)> = HashMap::new();
+        for frame in &self.frames {
+            counts.entry(frame.function).or_insert((0, 0)).0 += frame.count;
+        }
+        for (function, inclusive) in &self.inclusive {
+            counts.entry(*function).or_insert((0, 0)).1 += inclusive;
+        }
+        // functions still running count up to now
+        for (function, depth) in &self.depth {
+            if *depth > 0 {
+                let entered = self.entered.get(function).copied().unwrap_or(0);
+                counts.entry(*function).or_insert((0, 0)).1 += self.total - entered;
+            }
+        }
+        counts
+    }
+
+    pub fn report(&self) -> String {
+        let mut retr = format!("total instructions {}\n", self.total);
+
+        retr += &format!("\ntop {} addresses\n", self.top);
+        let mut addresses: Vec<usize> = (0..MEMORY_WORDS)
+            .filter(|a| self.address_counts[*a] > 0)
+            .collect();
+        addresses.sort_by(|a, b| self.address_counts[*b].cmp(&self.address_counts[*a]));
+        for address in addresses.iter().take(self.top) {
+            let count = self.address_counts[*address];
+            retr += &format!(
+                "{:>6} {:>12} {:>6.2}% {}\n",
+                address,
+                count,
+                percent(count, self.total),
+                OpCodes::parse(self.opcodes[*address])
+            );
+        }
+
+        retr += &format!("\ntop {} functions (self, inclusive)\n", self.top);
+        let counts = self.function_counts();
+        let mut functions: Vec<(&usize, &(u64, u64))> = counts.iter().collect();
+        functions.sort_by(|a, b| (b.1).0.cmp(&(a.1).0).then(a.0.cmp(b.0)));
+        for (function, (own, inclusive)) in functions.iter().take(self.top) {
+            retr += &format!(
+                "{:>10} {:>12} {:>6.2}% {:>12} {:>6.2}%\n",
+                function_name(**function),
+                own,
+                percent(*own, self.total),
+                inclusive,
+                percent(*inclusive, self.total)
+            );
+        }
+
+        retr += "\nopcodes\n";
+        let mut opcodes: Vec<(&u16, &u64)> = self.opcode_counts.iter().collect();
+        opcodes.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (opcode, count) in opcodes {
+            retr += &format!(
+                "{:>10} {:>12} {:>6.2}%\n",
+                OpCodes::parse(*opcode).to_string(),
+                count,
+                percent(*count, self.total)
+            );
+        }
+        retr
+    }
+
+    /// Call stacks in the folded format flamegraph tools read
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = self
+            .frames
+            .iter()
+            .enumerate()
+            .filter(|(_, frame)| frame.count > 0)
+            .map(|(idx, frame)| {
+                let names: Vec<String> = self.stack(idx).into_iter().map(function_name).collect();
+                format!("{} {}", names.join(";"), frame.count)
+            })
+            .collect();
+        lines.sort();
+        lines.join("\n") + "\n"
+    }
+
+    /// Writes the report and folded stacks to their configured files
+    pub fn save(&self) {
+        if let Some(path) = &self.report_path {
+            match fs::write(path, self.report()) {
+                Ok(_) => info!("wrote profile to {}", path.display()),
+                Err(what) => error!("Failed to write profile {} : {}", path.display(), what),
+            }
+        }
+        if let Some(path) = &self.folded_path {
+            match fs::write(path, self.folded()) {
+                Ok(_) => info!("wrote folded stacks to {}", path.display()),
+                Err(what) => error!("Failed to write {} : {}", path.display(), what),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hotspots_and_folded_stacks() {
+        // 0: call 5, 2: call 5, 4: halt, 5: call 8, 7: ret, 8: nop, 9: ret
+        let mut profiler = Profiler::new(None, None, 3);
+        let run = [
+            (0, 17, Some(5)),
+            (5, 17, Some(8)),
+            (8, 21, None),
+            (9, 18, None),
+            (7, 18, None),
+            (2, 17, Some(5)),
+            (5, 17, Some(8)),
+            (8, 21, None),
+            (9, 18, None),
+            (7, 18, None),
+            (4, 0, None),
+        ];
+        for (ip, opcode, target) in run.iter() {
+            profiler.record(*ip, *opcode, *target);
+        }
+
+        assert_eq!(profiler.total, 11);
+        assert_eq!(profiler.address_counts[8], 2);
+        assert_eq!(
+            profiler.folded(),
+            "entry 3\nentry;fn_5 4\nentry;fn_5;fn_8 4\n"
+        );
+        assert_eq!(profiler.function_counts()[&5], (4, 8));
+        assert_eq!(profiler.function_counts()[&ENTRY], (3, 11));
+        let report = profiler.report();
+        assert!(report.starts_with("total instructions 11\n"));
+        assert!(report.contains("      call            4  36.36%"));
+    }
+
+    #[test]
+    fn recursion_stays_flat() {
+        // 0: call 2, fn_2 calls itself at 5 ten times, then all return at 7
+        let mut profiler = Profiler::new(None, None, 3);
+        profiler.record(0, 17, Some(2));
+        for _ in 0..10 {
+            profiler.record(2, 8, None);
+            profiler.record(5, 17, Some(2));
+        }
+        for _ in 0..11 {
+            profiler.record(7, 18, None);
+        }
+        assert_eq!(profiler.frames.len(), 2);
+        assert_eq!(profiler.folded(), "entry 1\nentry;fn_2 31\n");
+        assert_eq!(profiler.function_counts()[&2], (31, 31));
+        assert_eq!(profiler.returns.len(), 0);
+    }
+}
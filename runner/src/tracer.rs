@@ -0,0 +1,221 @@
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path;
+
+use log::error;
+
+use code::opcodes::OpCodes;
+use code::trace::{TraceRecord, BINARY_MAGIC};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Text,
+    Binary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceMode {
+    /// every executed instruction
+    Instructions,
+    /// only calls and returns, indented by call depth
+    Calls,
+}
+
+/// Which instructions end up in the trace
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    /// inclusive address range
+    pub range: Option<(usize, usize)>,
+    /// only trace while this function is on the call stack
+    pub function: Option<usize>,
+}
+
+pub struct Tracer {
+    out: Box<dyn Write + Send>,
+    format: TraceFormat,
+    mode: TraceMode,
+    filter: TraceFilter,
+    pub enabled: bool,
+    depth: usize,
+    // call depth the filtered function was entered at
+    function_depth: Option<usize>,
+}
+
+impl Tracer {
+    pub fn create(
+        path: &path::Path,
+        format: TraceFormat,
+        mode: TraceMode,
+        filter: TraceFilter,
+    ) -> std::io::Result<Tracer> {
+        let file = fs::File::create(path)?;
+        Tracer::from_writer(Box::new(BufWriter::new(file)), format, mode, filter)
+    }
+
+    pub fn from_writer(
+        mut out: Box<dyn Write + Send>,
+        format: TraceFormat,
+        mode: TraceMode,
+        filter: TraceFilter,
+    ) -> std::io::Result<Tracer> {
+        if format == TraceFormat::Binary && mode == TraceMode::Instructions {
+            out.write_all(BINARY_MAGIC)?;
+        }
+        Ok(Tracer {
+            out,
+            format,
+            mode,
+            filter,
+            enabled: true,
+            depth: 0,
+            function_depth: None,
+        })
+    }
+
+    fn in_range(&self, ip: usize) -> bool {
+        match self.filter.range {
+            Some((from, to)) => ip >= from && ip <= to,
+            None => true,
+        }
+    }
+
+    fn in_function(&self) -> bool {
+        self.filter.function.is_none() || self.function_depth.is_some()
+    }
+
+    /// Records one executed instruction
+    pub fn record(&mut self, record: &TraceRecord) {
+        if !self.enabled {
+            return;
+        }
+        let op = record.op();
+        if op == OpCodes::call {
+            let target = record.values.first().copied().unwrap_or(0) as usize;
+            if self.function_depth.is_none() && self.filter.function == Some(target) {
+                self.function_depth = Some(self.depth);
+            }
+        }
+
+        let result = if self.in_function() && self.in_range(record.ip as usize) {
+            self.write(record, &op)
+        } else {
+            Ok(())
+        };
+        if let Err(what) = result {
+            error!("failed to write trace, tracing disabled: {}", what);
+            self.enabled = false;
+        }
+
+        match op {
+            OpCodes::call => self.depth += 1,
+            OpCodes::ret => {
+                // the trace may have started inside a call
+                self.depth = self.depth.saturating_sub(1);
+                if self.function_depth == Some(self.depth) {
+                    self.function_depth = None;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn write(&mut self, record: &TraceRecord, op: &OpCodes) -> std::io::Result<()> {
+        match (self.mode, self.format) {
+            (TraceMode::Instructions, TraceFormat::Text) => writeln!(self.out, "{}", record),
+            (TraceMode::Instructions, TraceFormat::Binary) => record.write_binary(&mut self.out),
+            (TraceMode::Calls, _) => {
+                let indent = "  ".repeat(self.depth);
+                match op {
+                    OpCodes::call => writeln!(
+                        self.out,
+                        "{}call {} from {}",
+                        indent, record.values[0], record.ip
+                    ),
+                    OpCodes::ret => writeln!(
+                        self.out,
+                        "{}ret from {}",
+                        "  ".repeat(self.depth.saturating_sub(1)),
+                        record.ip
+                    ),
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if let Err(what) = self.out.flush() {
+            error!("failed to flush trace: {}", what);
+        }
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::Host;
+    use code::program::Program;
+
+    // 0: call 4, 2: call 9, 4: set r0 7, 7: call 9, 9: ret
+    const PROGRAM: [u16; 10] = [17, 4, 17, 9, 1, 32768, 7, 17, 9, 18];
+
+    fn trace(name: &str, format: TraceFormat, mode: TraceMode, filter: TraceFilter) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("runner-trace-{}", name));
+        let mut program = Program::new();
+        program.data = PROGRAM.to_vec();
+        let mut host = Host::from(program);
+        host.set_tracer(Some(Tracer::create(&path, format, mode, filter).unwrap()));
+        for _ in 0..7 {
+            host.step();
+        }
+        host.set_tracer(None);
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        data
+    }
+
+    #[test]
+    fn instruction_trace() {
+        let filter = TraceFilter {
+            range: Some((4, 8)),
+            function: None,
+        };
+        let data = trace("text", TraceFormat::Text, TraceMode::Instructions, filter);
+        assert_eq!(
+            String::from_utf8(data).unwrap(),
+            "1 | 4 | set reg0 7 | 0 7 | reg0=7\n2 | 7 | call 9 | 9 | \n"
+        );
+    }
+
+    #[test]
+    fn binary_trace() {
+        let data = trace(
+            "binary",
+            TraceFormat::Binary,
+            TraceMode::Instructions,
+            TraceFilter::default(),
+        );
+        let records = code::trace::read_trace(&mut &data[..]).unwrap();
+        assert_eq!(records.len(), 7);
+        assert_eq!(records[1].writes[0].value, 7);
+    }
+
+    #[test]
+    fn call_trace_for_function() {
+        let filter = TraceFilter {
+            range: None,
+            function: Some(4),
+        };
+        let data = trace("calls", TraceFormat::Text, TraceMode::Calls, filter);
+        assert_eq!(
+            String::from_utf8(data).unwrap(),
+            "call 4 from 0\n  call 9 from 7\n  ret from 9\nret from 9\n"
+        );
+    }
+}
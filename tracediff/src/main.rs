@@ -0,0 +1,293 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::process;
+
+use code::trace::{read_trace, TraceRecord};
+
+// Compares two execution traces recorded by the runner (--trace) and reports
+// where they first diverge.
+//
+// exit status: 0 traces match, 1 traces diverge, 2 error
+
+const COLUMN_WIDTH: usize = 60;
+
+struct Config {
+    left: String,
+    right: String,
+    context: usize,
+}
+
+impl Config {
+    fn new(args: &[String]) -> Result<Config, String> {
+        let mut files = Vec::new();
+        let mut context = 5;
+        let mut rest = args[1..].iter();
+        while let Some(arg) = rest.next() {
+            match arg.as_ref() {
+                "--context" | "-C" => {
+                    context = match rest.next().map(|c| c.parse::<usize>()) {
+                        Some(Ok(context)) => context,
+                        _ => return Err(format!("{} needs a number", arg)),
+                    }
+                }
+                _ => files.push(arg.clone()),
+            }
+        }
+        if files.len() != 2 {
+            return Err("usage: tracediff [--context N] TRACE_A TRACE_B".to_string());
+        }
+        Ok(Config {
+            left: files[0].clone(),
+            right: files[1].clone(),
+            context,
+        })
+    }
+}
+
+/// Vm state as far as it can be reconstructed from a trace
+#[derive(Default)]
+struct State {
+    registers: [Option<u16>; 8],
+    memory: BTreeMap<u16, u16>,
+}
+
+impl State {
+    /// Operands show register contents before the instruction runs
+    fn observe(&mut self, record: &TraceRecord) {
+        for (operand, value) in record.operands.iter().zip(record.values.iter()) {
+            if let 32768..=32775 = operand {
+                self.registers[(operand - 32768) as usize] = Some(*value);
+            }
+        }
+    }
+
+    fn apply(&mut self, record: &TraceRecord) {
+        self.observe(record);
+        for write in &record.writes {
+            match write.target {
+                32768..=32775 => {
+                    self.registers[(write.target - 32768) as usize] = Some(write.value)
+                }
+                _ => {
+                    self.memory.insert(write.target, write.value);
+                }
+            }
+        }
+    }
+}
+
+fn same_instruction(a: &TraceRecord, b: &TraceRecord) -> bool {
+    a.ip == b.ip && a.opcode == b.opcode && a.operands == b.operands
+}
+
+fn same_effect(a: &TraceRecord, b: &TraceRecord) -> bool {
+    same_instruction(a, b) && a.values == b.values && a.writes == b.writes
+}
+
+/// Index of the first record that differs, None if the traces match
+fn find_divergence(left: &[TraceRecord], right: &[TraceRecord]) -> Option<usize> {
+    let common = usize::min(left.len(), right.len());
+    match (0..common).find(|idx| !same_effect(&left[*idx], &right[*idx])) {
+        Some(idx) => Some(idx),
+        None if left.len() != right.len() => Some(common),
+        None => None,
+    }
+}
+
+fn format_value(value: Option<u16>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "?".to_string(),
+    }
+}
+
+fn state_diff(left: &State, right: &State) -> Vec<String> {
+    let mut lines = Vec::new();
+    for reg in 0..8 {
+        if left.registers[reg] != right.registers[reg] {
+            lines.push(format!(
+                "  reg{}: {} vs {}",
+                reg,
+                format_value(left.registers[reg]),
+                format_value(right.registers[reg])
+            ));
+        }
+    }
+    let mut addresses: Vec<&u16> = left.memory.keys().chain(right.memory.keys()).collect();
+    addresses.sort();
+    addresses.dedup();
+    for address in addresses {
+        let a = left.memory.get(address).copied();
+        let b = right.memory.get(address).copied();
+        if a != b {
+            lines.push(format!(
+                "  mem[{}]: {} vs {}",
+                address,
+                format_value(a),
+                format_value(b)
+            ));
+        }
+    }
+    lines
+}
+
+fn column(records: &[TraceRecord], idx: usize) -> String {
+    match records.get(idx) {
+        Some(record) => {
+            let mut text = record.to_string();
+            text.truncate(COLUMN_WIDTH);
+            text
+        }
+        None => "<end of trace>".to_string(),
+    }
+}
+
+fn report(config: &Config, left: &[TraceRecord], right: &[TraceRecord], idx: usize) {
+    println!("traces diverge at record {}", idx);
+    match (left.get(idx), right.get(idx)) {
+        (Some(a), Some(b)) if a.ip != b.ip => {
+            println!("  execution continued at {} vs {}", a.ip, b.ip)
+        }
+        (Some(a), Some(b)) if !same_instruction(a, b) => {
+            println!(
+                "  different code at {}: {} {:?} vs {} {:?}",
+                a.ip,
+                a.op(),
+                a.operands,
+                b.op(),
+                b.operands
+            )
+        }
+        (Some(a), Some(_)) => println!("  same instruction at {}, different values", a.ip),
+        (None, _) => println!("  {} ended", config.left),
+        (_, None) => println!("  {} ended", config.right),
+    }
+
+    let mut left_state = State::default();
+    let mut right_state = State::default();
+    for record in &left[..idx] {
+        left_state.apply(record);
+    }
+    for record in &right[..idx] {
+        right_state.apply(record);
+    }
+    if let Some(record) = left.get(idx) {
+        left_state.observe(record);
+    }
+    if let Some(record) = right.get(idx) {
+        right_state.observe(record);
+    }
+    let diff = state_diff(&left_state, &right_state);
+    if diff.is_empty() {
+        println!("no state difference before the divergent instruction");
+    } else {
+        println!(
+            "state before the divergent instruction ({} vs {}):",
+            config.left, config.right
+        );
+        for line in diff {
+            println!("{}", line);
+        }
+    }
+
+    println!();
+    println!(
+        "  {:<width$} | {}",
+        config.left,
+        config.right,
+        width = COLUMN_WIDTH
+    );
+    let from = idx.saturating_sub(config.context);
+    let to = idx + config.context;
+    for line in from..=to {
+        if line >= left.len() && line >= right.len() {
+            break;
+        }
+        let marker = if line == idx { ">" } else { " " };
+        println!(
+            "{} {:<width$} | {}",
+            marker,
+            column(left, line),
+            column(right, line),
+            width = COLUMN_WIDTH
+        );
+    }
+}
+
+fn load(path: &str) -> Result<Vec<TraceRecord>, String> {
+    let file = fs::File::open(path).map_err(|e| format!("Failed to open {} : {}", path, e))?;
+    read_trace(&mut io::BufReader::new(file))
+        .map_err(|e| format!("Failed to read {} : {}", path, e))
+}
+
+fn run(config: &Config) -> Result<bool, String> {
+    let left = load(&config.left)?;
+    let right = load(&config.right)?;
+    match find_divergence(&left, &right) {
+        Some(idx) => {
+            report(config, &left, &right, idx);
+            Ok(false)
+        }
+        None => {
+            println!("traces match, {} records", left.len());
+            Ok(true)
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let result = Config::new(&args).and_then(|config| run(&config));
+    match result {
+        Ok(true) => process::exit(0),
+        Ok(false) => process::exit(1),
+        Err(what) => {
+            eprintln!("{}", what);
+            process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use code::trace::TraceWrite;
+
+    fn record(ip: u16, value: u16) -> TraceRecord {
+        // set reg0 value
+        TraceRecord {
+            count: ip as u64,
+            ip,
+            opcode: 1,
+            operands: vec![32768, value],
+            values: vec![0, value],
+            writes: vec![TraceWrite {
+                target: 32768,
+                value,
+            }],
+        }
+    }
+
+    #[test]
+    fn divergence() {
+        let a = vec![record(0, 1), record(3, 2), record(6, 3)];
+        let b = vec![record(0, 1), record(3, 5), record(6, 3)];
+        assert_eq!(find_divergence(&a, &a), None);
+        assert_eq!(find_divergence(&a, &b), Some(1));
+        assert_eq!(find_divergence(&a, &a[..2]), Some(2));
+    }
+
+    #[test]
+    fn reconstructed_state() {
+        let mut left = State::default();
+        let mut right = State::default();
+        left.apply(&record(0, 1));
+        right.apply(&record(0, 4));
+        assert_eq!(
+            state_diff(&left, &right),
+            vec!["  reg0: 1 vs 4".to_string()]
+        );
+    }
+}
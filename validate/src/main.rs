@@ -0,0 +1,140 @@
+use std::env;
+use std::path;
+use std::process;
+
+use serde_json::json;
+
+use code::program::Program;
+use code::validate::{validate, Finding, Severity};
+
+// Checks a program for invalid operands, writes to literals, bad jump
+// targets and unprintable output without running it.
+//
+// Findings are printed one per line as
+//   ADDRESS: SEVERITY CHECK: message
+// or with --json as one json object per line:
+//   {"address":0,"severity":"warning","check":"literal-destination","message":"..."}
+//
+// exit status: 0 nothing at or above --fail-on, 1 findings, 2 error
+
+struct Config {
+    input: String,
+    json: bool,
+    // findings below this aren't shown
+    level: Severity,
+    fail_on: Severity,
+    strict: bool,
+}
+
+fn severity(arg: &str, value: Option<&String>) -> Result<Severity, String> {
+    value
+        .and_then(|name| Severity::from_name(name))
+        .ok_or(format!("{} needs one of info, warning, error", arg))
+}
+
+impl Config {
+    fn new(args: &[String]) -> Result<Config, String> {
+        let mut files = Vec::new();
+        let mut json = false;
+        let mut level = Severity::Info;
+        let mut fail_on = Severity::Error;
+        let mut strict = false;
+        let mut rest = args[1..].iter();
+        while let Some(arg) = rest.next() {
+            match arg.as_ref() {
+                "--json" => json = true,
+                "--level" => level = severity(arg, rest.next())?,
+                "--fail-on" => fail_on = severity(arg, rest.next())?,
+                "--strict" => strict = true,
+                _ => files.push(arg.clone()),
+            }
+        }
+        if files.len() != 1 {
+            return Err(
+                "usage: validate [--json] [--level SEVERITY] [--fail-on SEVERITY] [--strict] PROGRAM"
+                    .to_string(),
+            );
+        }
+        Ok(Config {
+            input: files[0].clone(),
+            json,
+            level,
+            fail_on,
+            strict,
+        })
+    }
+}
+
+fn to_json(finding: &Finding) -> String {
+    json!({
+        "address": finding.address,
+        "severity": finding.severity().to_string(),
+        "check": finding.check.name(),
+        "message": finding.message,
+    })
+    .to_string()
+}
+
+fn run(config: &Config) -> Result<bool, String> {
+    let program = Program::load(path::Path::new(&config.input), None, config.strict)
+        .map_err(|e| format!("{}: {}", config.input, e))?;
+    for warning in &program.warnings {
+        eprintln!("{}: {}", config.input, warning);
+    }
+    let findings = validate(&program.data);
+    // hiding findings doesn't change whether they fail the check
+    let passed = findings.iter().all(|f| f.severity() < config.fail_on);
+    let findings: Vec<Finding> = findings
+        .into_iter()
+        .filter(|f| f.severity() >= config.level)
+        .collect();
+    for finding in &findings {
+        if config.json {
+            println!("{}", to_json(finding));
+        } else {
+            println!("{}", finding);
+        }
+    }
+    if !config.json {
+        let count = |severity| findings.iter().filter(|f| f.severity() == severity).count();
+        eprintln!(
+            "{} errors, {} warnings, {} info",
+            count(Severity::Error),
+            count(Severity::Warning),
+            count(Severity::Info)
+        );
+    }
+    Ok(passed)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let result = Config::new(&args).and_then(|config| run(&config));
+    match result {
+        Ok(true) => process::exit(0),
+        Ok(false) => process::exit(1),
+        Err(what) => {
+            eprintln!("{}", what);
+            process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use code::validate::Check;
+
+    #[test]
+    fn json_output() {
+        let finding = Finding {
+            address: 7,
+            check: Check::JumpOutside,
+            message: "jmp to 100".to_string(),
+        };
+        let value: serde_json::Value = serde_json::from_str(&to_json(&finding)).unwrap();
+        assert_eq!(value["address"], 7);
+        assert_eq!(value["severity"], "error");
+        assert_eq!(value["check"], "jump-outside");
+    }
+}